@@ -0,0 +1,375 @@
+use async_openai::types::{
+    ChatChoice, ChatCompletionMessageToolCall, ChatCompletionResponseMessage, ChatCompletionToolType, CompletionUsage,
+    CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse, CreateCompletionRequest,
+    CreateCompletionResponse, CreateEmbeddingRequest, CreateEmbeddingResponse, FunctionCall, Role,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use crate::{Digest, RequestContent, RequestKind};
+
+/// Raw OpenAI request carried by a metered round.
+#[derive(Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum ApiRequest {
+    Completion(CreateCompletionRequest),
+    Chat(CreateChatCompletionRequest),
+    Embedding(CreateEmbeddingRequest),
+}
+
+impl ApiRequest {
+    pub fn is_stream(&self) -> bool {
+        match self {
+            ApiRequest::Completion(req) => req.stream == Some(true),
+            ApiRequest::Chat(req) => req.stream == Some(true),
+            ApiRequest::Embedding(_) => false,
+        }
+    }
+
+    /// Output cap of a request, `None` if unbounded or an embedding.
+    #[allow(deprecated)]
+    pub fn max_tokens(&self) -> Option<u32> {
+        match self {
+            ApiRequest::Completion(req) => req.max_tokens,
+            ApiRequest::Chat(req) => req.max_completion_tokens.or(req.max_tokens),
+            ApiRequest::Embedding(_) => None,
+        }
+    }
+
+    /// Caps the output of a request, embeddings have none.
+    pub fn clamp_max_tokens(&mut self, limit: u32) {
+        match self {
+            ApiRequest::Completion(req) => {
+                req.max_tokens = Some(req.max_tokens.map_or(limit, |max| max.min(limit)));
+            }
+            #[allow(deprecated)]
+            ApiRequest::Chat(req) => {
+                match (req.max_completion_tokens, req.max_tokens) {
+                    (Some(max), _) => req.max_completion_tokens = Some(max.min(limit)),
+                    (None, max) => req.max_tokens = Some(max.map_or(limit, |max| max.min(limit))),
+                }
+            }
+            ApiRequest::Embedding(_) => (),
+        }
+    }
+
+    pub fn content(&self) -> serde_json::Result<RequestContent> {
+        let content = match self {
+            ApiRequest::Completion(req) => RequestContent {
+                kind: RequestKind::Completion,
+                model: req.model.clone(),
+                input: canonical_json(&req.prompt)?,
+                max_tokens: req.max_tokens,
+            },
+            #[allow(deprecated)]
+            ApiRequest::Chat(req) => RequestContent {
+                kind: RequestKind::Chat,
+                model: req.model.clone(),
+                input: canonical_json(&req.messages)?,
+                max_tokens: req.max_completion_tokens.or(req.max_tokens),
+            },
+            ApiRequest::Embedding(req) => RequestContent {
+                kind: RequestKind::Embedding,
+                model: req.model.clone(),
+                input: canonical_json(&req.input)?,
+                max_tokens: None,
+            },
+        };
+        Ok(content)
+    }
+}
+
+/// JSON with object keys sorted bytewise, no whitespace and null fields dropped, so the signed
+/// digest doesn't depend on how the OpenAI types order, add or skip optional fields.
+pub fn canonical_json(value: &impl Serialize) -> serde_json::Result<String> {
+    let mut out = String::new();
+    write_canonical(&serde_json::to_value(value)?, &mut out)?;
+    Ok(out)
+}
+
+fn write_canonical(value: &Value, out: &mut String) -> serde_json::Result<()> {
+    match value {
+        Value::Object(map) => {
+            let mut fields = map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .collect::<Vec<_>>();
+            fields.sort_by(|a, b| a.0.cmp(b.0));
+
+            out.push('{');
+
+            for (i, (key, v)) in fields.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical(v, out)?;
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(v, out)?;
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&serde_json::to_string(scalar)?),
+    }
+    Ok(())
+}
+
+/// Raw OpenAI response delivered for a metered round.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ApiResponse {
+    Completion(CreateCompletionResponse),
+    Chat(CreateChatCompletionResponse),
+    // streamed chat chunks as delivered to the client
+    ChatStream(Vec<CreateChatCompletionStreamResponse>),
+    Embedding(CreateEmbeddingResponse),
+}
+
+impl ApiResponse {
+    pub fn usage(&self) -> Option<CompletionUsage> {
+        match self {
+            ApiResponse::Completion(resp) => resp.usage.clone(),
+            ApiResponse::Chat(resp) => resp.usage.clone(),
+            // the final chunk carries it when the request asked for `include_usage`
+            ApiResponse::ChatStream(chunks) => chunks.iter().rev().find_map(|c| c.usage.clone()),
+            // embeddings only bill the input
+            ApiResponse::Embedding(resp) => Some(CompletionUsage {
+                prompt_tokens: resp.usage.prompt_tokens,
+                completion_tokens: 0,
+                total_tokens: resp.usage.total_tokens,
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
+            }),
+        }
+    }
+
+    /// Digest the client signs in `ConfirmMsg`, both sides hash their deserialized copy.
+    pub fn digest(&self) -> serde_json::Result<Digest> {
+        let buf = serde_json::to_vec(self)?;
+        Ok(Sha256::digest(&buf).into())
+    }
+
+    /// Body as the OpenAI API would return it, without the kind tag.
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        match self {
+            ApiResponse::Completion(resp) => serde_json::to_vec(resp),
+            ApiResponse::Chat(resp) => serde_json::to_vec(resp),
+            ApiResponse::ChatStream(chunks) => serde_json::to_vec(&merge_chat_chunks(chunks)),
+            ApiResponse::Embedding(resp) => serde_json::to_vec(resp),
+        }
+    }
+}
+
+/// The response the same request would have returned without streaming.
+fn merge_chat_chunks(chunks: &[CreateChatCompletionStreamResponse]) -> Option<CreateChatCompletionResponse> {
+    let first = chunks.first()?;
+    let mut choices: Vec<ChatChoice> = Vec::new();
+
+    for chunk in chunks {
+        for delta in &chunk.choices {
+            let pos = match choices.iter().position(|c| c.index == delta.index) {
+                Some(pos) => pos,
+                None => {
+                    #[allow(deprecated)]
+                    choices.push(ChatChoice {
+                        index: delta.index,
+                        message: ChatCompletionResponseMessage {
+                            content: None,
+                            refusal: None,
+                            tool_calls: None,
+                            role: Role::Assistant,
+                            function_call: None,
+                            audio: None,
+                        },
+                        finish_reason: None,
+                        logprobs: None,
+                    });
+                    choices.len() - 1
+                }
+            };
+
+            let choice = &mut choices[pos];
+            let msg = &mut choice.message;
+
+            if let Some(role) = delta.delta.role {
+                msg.role = role;
+            }
+
+            if let Some(content) = &delta.delta.content {
+                msg.content.get_or_insert_with(String::new).push_str(content);
+            }
+
+            if let Some(refusal) = &delta.delta.refusal {
+                msg.refusal.get_or_insert_with(String::new).push_str(refusal);
+            }
+
+            // tool calls arrive in pieces addressed by their index
+            for call in delta.delta.tool_calls.iter().flatten() {
+                let calls = msg.tool_calls.get_or_insert_with(Vec::new);
+
+                while calls.len() <= call.index as usize {
+                    calls.push(ChatCompletionMessageToolCall {
+                        id: String::new(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall { name: String::new(), arguments: String::new() },
+                    });
+                }
+
+                let acc = &mut calls[call.index as usize];
+
+                if let Some(id) = &call.id {
+                    acc.id.clone_from(id);
+                }
+
+                if let Some(function) = &call.function {
+                    if let Some(name) = &function.name {
+                        acc.function.name.push_str(name);
+                    }
+
+                    if let Some(arguments) = &function.arguments {
+                        acc.function.arguments.push_str(arguments);
+                    }
+                }
+            }
+
+            if delta.finish_reason.is_some() {
+                choice.finish_reason = delta.finish_reason;
+            }
+        }
+    }
+
+    Some(CreateChatCompletionResponse {
+        id: first.id.clone(),
+        choices,
+        created: first.created,
+        model: first.model.clone(),
+        service_tier: first.service_tier.clone(),
+        system_fingerprint: first.system_fingerprint.clone(),
+        object: "chat.completion".to_string(),
+        usage: chunks.iter().rev().find_map(|c| c.usage.clone()),
+    })
+}
+
+/// Folds the chunks of a streamed response into what gets stored and confirmed,
+/// gateway and bridge must use the same aggregator so both sides see the same usage.
+pub trait StreamAggregator {
+    type Chunk: Serialize + DeserializeOwned;
+
+    fn push(&mut self, chunk: Self::Chunk);
+
+    fn finish(self) -> Option<ApiResponse>;
+}
+
+#[derive(Default)]
+pub struct CompletionAggregator {
+    resp: Option<CreateCompletionResponse>,
+}
+
+impl StreamAggregator for CompletionAggregator {
+    type Chunk = CreateCompletionResponse;
+
+    fn push(&mut self, chunk: CreateCompletionResponse) {
+        match &mut self.resp {
+            None => self.resp = Some(chunk),
+            Some(acc) => {
+                for choice in chunk.choices {
+                    match acc.choices.iter_mut().find(|c| c.index == choice.index) {
+                        Some(c) => {
+                            c.text.push_str(&choice.text);
+
+                            if choice.finish_reason.is_some() {
+                                c.finish_reason = choice.finish_reason;
+                            }
+                        }
+                        None => acc.choices.push(choice),
+                    }
+                }
+
+                if chunk.usage.is_some() {
+                    acc.usage = chunk.usage;
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Option<ApiResponse> {
+        // usage comes from the final chunk the backend sends for `include_usage`
+        self.resp.map(ApiResponse::Completion)
+    }
+}
+
+#[derive(Default)]
+pub struct ChatAggregator {
+    chunks: Vec<CreateChatCompletionStreamResponse>,
+}
+
+impl StreamAggregator for ChatAggregator {
+    type Chunk = CreateChatCompletionStreamResponse;
+
+    fn push(&mut self, chunk: CreateChatCompletionStreamResponse) {
+        self.chunks.push(chunk);
+    }
+
+    fn finish(self) -> Option<ApiResponse> {
+        if self.chunks.is_empty() {
+            return None;
+        }
+        Some(ApiResponse::ChatStream(self.chunks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn chunk(choices: serde_json::Value, usage: serde_json::Value) -> CreateChatCompletionStreamResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "m",
+            "choices": choices,
+            "usage": usage,
+        })).unwrap()
+    }
+
+    #[test]
+    fn canonical_json_ignores_field_order_and_nulls() {
+        let a = json!({"role": "user", "content": "hi", "name": null, "parts": [{"b": 1, "a": [null, "x"]}]});
+        let b = json!({"parts": [{"a": [null, "x"], "b": 1}], "content": "hi", "role": "user"});
+
+        assert_eq!(canonical_json(&a).unwrap(), canonical_json(&b).unwrap());
+        assert_eq!(canonical_json(&a).unwrap(), r#"{"content":"hi","parts":[{"a":[null,"x"],"b":1}],"role":"user"}"#);
+    }
+
+    #[test]
+    fn chat_stream_merges_into_completion() {
+        let chunks = vec![
+            chunk(json!([{"index": 0, "delta": {"role": "assistant", "content": "Hel"}}]), json!(null)),
+            chunk(json!([{"index": 0, "delta": {"content": "lo"}, "finish_reason": "stop"}]), json!(null)),
+            chunk(json!([]), json!({"prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5})),
+        ];
+
+        let resp = ApiResponse::ChatStream(chunks);
+        assert_eq!(resp.usage().unwrap().completion_tokens, 2);
+
+        let merged: CreateChatCompletionResponse = serde_json::from_slice(&resp.to_json().unwrap()).unwrap();
+        assert_eq!(merged.object, "chat.completion");
+        assert_eq!(merged.choices.len(), 1);
+        assert_eq!(merged.choices[0].message.content.as_deref(), Some("Hello"));
+        assert_eq!(merged.usage.unwrap().total_tokens, 5);
+
+        assert!(ApiResponse::ChatStream(vec![chunk(json!([]), json!(null))]).usage().is_none());
+    }
+}
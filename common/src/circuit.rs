@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use ed25519_dalek::{ed25519, Verifier, VerifyingKey};
+use crate::journal::ClaimJournal;
+use crate::{merkle, Claim, ConfirmMsg, Digest, Input, PublicKey, Segment, DIGEST_SIZE, METER_UNITS, SIGNATURE_SIZE};
+
+/// Why the circuit rejects its input, the guests panic with it and the host checks for it before proving.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CircuitError {
+    NoSegments,
+    EmptyRounds(PublicKey),
+    InvalidPublicKey(PublicKey),
+    SeqMismatch { pk: PublicKey, expected: u32, got: u32 },
+    SeqOverflow(PublicKey),
+    SignatureLength { pk: PublicKey, seq: u32, len: usize },
+    InvalidSignature { pk: PublicKey, seq: u32 },
+    UnitsOverflow { pk: PublicKey, seq: u32 },
+    DomainMismatch(PublicKey),
+}
+
+struct Pk<'a>(&'a PublicKey);
+
+impl fmt::Display for Pk<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitError::NoSegments => write!(f, "no segments to claim"),
+            CircuitError::EmptyRounds(pk) => write!(f, "client {}: no rounds", Pk(pk)),
+            CircuitError::InvalidPublicKey(pk) => write!(f, "client {}: invalid public key", Pk(pk)),
+            CircuitError::SeqMismatch { pk, expected, got } => write!(f, "client {}: expected seq {}, got {}", Pk(pk), expected, got),
+            CircuitError::SeqOverflow(pk) => write!(f, "client {}: seq overflow", Pk(pk)),
+            CircuitError::SignatureLength { pk, seq, len } => write!(f, "client {}, seq {}: signature of {} bytes", Pk(pk), seq, len),
+            CircuitError::InvalidSignature { pk, seq } => write!(f, "client {}, seq {}: invalid signature", Pk(pk), seq),
+            CircuitError::UnitsOverflow { pk, seq } => write!(f, "client {}, seq {}: units consumed overflow", Pk(pk), seq),
+            CircuitError::DomainMismatch(pk) => write!(f, "client {}: segment signed for another domain", Pk(pk)),
+        }
+    }
+}
+
+impl std::error::Error for CircuitError {}
+
+impl CircuitError {
+    /// Seq of the round the error is about, none if it is about the whole input.
+    pub fn seq(&self) -> Option<u32> {
+        match self {
+            CircuitError::SeqMismatch { expected, .. } => Some(*expected),
+            CircuitError::SignatureLength { seq, .. } => Some(*seq),
+            CircuitError::InvalidSignature { seq, .. } => Some(*seq),
+            CircuitError::UnitsOverflow { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+}
+
+fn verify(vk: &VerifyingKey, seq: u32, msg: &[u8], signature: &[u8]) -> Result<(), CircuitError> {
+    let pk = vk.to_bytes();
+
+    let signature = <[u8; SIGNATURE_SIZE]>::try_from(signature)
+        .map_err(|_| CircuitError::SignatureLength { pk, seq, len: signature.len() })?;
+
+    vk.verify(msg, &ed25519::Signature::from_bytes(&signature))
+        .map_err(|_| CircuitError::InvalidSignature { pk, seq })
+}
+
+// seq following `rounds` rounds from `start_seq`
+fn seq_after(pk: &PublicKey, start_seq: u32, rounds: usize) -> Result<u32, CircuitError> {
+    u32::try_from(rounds).ok()
+        .and_then(|rounds| start_seq.checked_add(rounds))
+        .ok_or(CircuitError::SeqOverflow(*pk))
+}
+
+fn add_units(total: &mut [u64; METER_UNITS], amounts: impl IntoIterator<Item = u64>, pk: &PublicKey, seq: u32) -> Result<(), CircuitError> {
+    for (total, amount) in total.iter_mut().zip(amounts) {
+        *total = total.checked_add(amount).ok_or(CircuitError::UnitsOverflow { pk: *pk, seq })?;
+    }
+    Ok(())
+}
+
+/// What the rounds guest commits for `input`.
+pub fn prove_rounds(input: &Input) -> Result<Vec<Segment>, CircuitError> {
+    let mut segments = Vec::with_capacity(input.rounds.len());
+
+    for (client_pk, rounds) in &input.rounds {
+        let start_seq = rounds.first().ok_or(CircuitError::EmptyRounds(*client_pk))?.request.msg.seq;
+        let last_seq = seq_after(client_pk, start_seq, rounds.len() - 1)?;
+        let vk = VerifyingKey::from_bytes(client_pk).map_err(|_| CircuitError::InvalidPublicKey(*client_pk))?;
+
+        let mut units_consumed = [0u64; METER_UNITS];
+        let mut leaves = Vec::with_capacity(rounds.len());
+
+        for (curr_seq, round) in (start_seq..=last_seq).zip(rounds) {
+            if round.request.msg.seq != curr_seq {
+                return Err(CircuitError::SeqMismatch { pk: *client_pk, expected: curr_seq, got: round.request.msg.seq });
+            }
+
+            let msg = input.domain.request_payload(round.request.msg);
+            // the signed req_hash is all the circuit needs, the gateway matched it to the content
+            verify(&vk, curr_seq, &msg, &round.request.signature)?;
+
+            // an unconfirmed round is provable by its request alone, it bills nothing
+            let confirm_msg = match &round.confirm {
+                Some(confirm) => {
+                    if confirm.msg.seq != curr_seq {
+                        return Err(CircuitError::SeqMismatch { pk: *client_pk, expected: curr_seq, got: confirm.msg.seq });
+                    }
+
+                    let msg = input.domain.confirm_payload(confirm.msg);
+                    verify(&vk, curr_seq, &msg, &confirm.signature)?;
+                    confirm.msg
+                }
+                None => ConfirmMsg::void(curr_seq),
+            };
+
+            add_units(&mut units_consumed, confirm_msg.usage.map(u64::from), client_pk, curr_seq)?;
+            leaves.push(merkle::leaf(round.request.msg, confirm_msg));
+        }
+
+        segments.push(Segment {
+            domain: input.domain,
+            pk: *client_pk,
+            start_seq,
+            units_consumed,
+            leaves,
+        });
+    }
+
+    Ok(segments)
+}
+
+struct Merged {
+    start_seq: u32,
+    units_consumed: [u64; METER_UNITS],
+    leaves: Vec<Digest>,
+}
+
+/// Encoded `ClaimJournal` the aggregation guest commits for verified segment journals.
+pub fn merge_segments(segment_image_id: [u32; 8], journals: &[Vec<Segment>]) -> Result<Vec<u8>, CircuitError> {
+    let mut merged: BTreeMap<PublicKey, Merged> = BTreeMap::new();
+    let domain = journals.iter().flatten().next().ok_or(CircuitError::NoSegments)?.domain;
+
+    for segment in journals.iter().flatten() {
+        if segment.leaves.is_empty() {
+            return Err(CircuitError::EmptyRounds(segment.pk));
+        }
+
+        // one claim settles with one provider of one deployment
+        if segment.domain != domain {
+            return Err(CircuitError::DomainMismatch(segment.pk));
+        }
+
+        let m = merged.entry(segment.pk).or_insert_with(|| Merged {
+            start_seq: segment.start_seq,
+            units_consumed: [0; METER_UNITS],
+            leaves: Vec::new(),
+        });
+
+        // segments of a client follow each other without gaps
+        let expected = seq_after(&segment.pk, m.start_seq, m.leaves.len())?;
+
+        if segment.start_seq != expected {
+            return Err(CircuitError::SeqMismatch { pk: segment.pk, expected, got: segment.start_seq });
+        }
+
+        add_units(&mut m.units_consumed, segment.units_consumed, &segment.pk, segment.start_seq)?;
+        m.leaves.extend_from_slice(&segment.leaves);
+    }
+
+    let claims = merged.into_iter()
+        .map(|(pk, m)| {
+            Ok(Claim {
+                pk,
+                start_seq: m.start_seq,
+                rounds: u32::try_from(m.leaves.len()).map_err(|_| CircuitError::SeqOverflow(pk))?,
+                units_consumed: m.units_consumed,
+                transcript_root: merkle::root(&m.leaves),
+            })
+        })
+        .collect::<Result<Vec<_>, CircuitError>>()?;
+
+    // laid out like the zkvm digest of the image id
+    let mut image_id = [0u8; DIGEST_SIZE];
+
+    for (buf, word) in image_id.chunks_exact_mut(4).zip(segment_image_id) {
+        buf.copy_from_slice(&word.to_le_bytes());
+    }
+
+    // the contract only accepts segments of the rounds guest it was deployed with
+    Ok(ClaimJournal {
+        domain,
+        claims,
+        segment_image_id: image_id,
+    }.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Domain;
+
+    fn segment(pk: u8, start_seq: u32, rounds: usize, units: u64) -> Segment {
+        Segment {
+            domain: Domain::new(1, [1; 20], [2; 20]),
+            pk: [pk; 32],
+            start_seq,
+            units_consumed: [units; METER_UNITS],
+            leaves: vec![[pk; 32]; rounds],
+        }
+    }
+
+    #[test]
+    fn merge_rejects_malformed_segments() {
+        assert_eq!(merge_segments([0; 8], &[]), Err(CircuitError::NoSegments));
+        assert_eq!(merge_segments([0; 8], &[vec![segment(1, 1, 0, 0)]]), Err(CircuitError::EmptyRounds([1; 32])));
+
+        let gap = [vec![segment(1, 1, 2, 0)], vec![segment(1, 4, 1, 0)]];
+        assert_eq!(merge_segments([0; 8], &gap), Err(CircuitError::SeqMismatch { pk: [1; 32], expected: 3, got: 4 }));
+
+        let overflow = [vec![segment(1, 1, 1, u64::MAX), segment(1, 2, 1, 1)]];
+        assert_eq!(merge_segments([0; 8], &overflow), Err(CircuitError::UnitsOverflow { pk: [1; 32], seq: 2 }));
+
+        let seq_overflow = [vec![segment(1, u32::MAX, 1, 0), segment(1, 0, 1, 0)]];
+        assert_eq!(merge_segments([0; 8], &seq_overflow), Err(CircuitError::SeqOverflow([1; 32])));
+
+        let mut other_provider = segment(2, 1, 1, 0);
+        other_provider.domain.provider = [3; 20];
+        let domains = [vec![segment(1, 1, 1, 0)], vec![other_provider]];
+        assert_eq!(merge_segments([0; 8], &domains), Err(CircuitError::DomainMismatch([2; 32])));
+
+        let ok = [vec![segment(2, 1, 2, 5), segment(1, 7, 1, 0)], vec![segment(2, 3, 1, 5)]];
+        let journal = ClaimJournal::try_from(merge_segments([0; 8], &ok).unwrap().as_slice()).unwrap();
+        assert_eq!(journal.claims.iter().map(|c| (c.pk[0], c.start_seq, c.rounds)).collect::<Vec<_>>(), [(1, 7, 1), (2, 1, 3)]);
+        assert_eq!(journal.claims[1].units_consumed, [10; METER_UNITS]);
+    }
+}
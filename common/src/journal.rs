@@ -0,0 +1,130 @@
+use std::fmt;
+use crate::{Claim, Digest, Domain, CLAIM_SIZE, DIGEST_SIZE, DOMAIN_SIZE};
+
+/// Layout of the claim journal, bumped whenever the contract has to rebuild it differently.
+pub const JOURNAL_VERSION: u8 = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JournalError {
+    Version(u8),
+    Length(usize),
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Version(v) => write!(f, "unsupported journal version {}", v),
+            JournalError::Length(len) => write!(f, "malformed journal of {} bytes", len),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+/// Journal the aggregation guest commits and the contract rebuilds from its calldata:
+/// the version byte, the domain the rounds were signed for, every claim in public key order,
+/// then the segment image id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClaimJournal {
+    pub domain: Domain,
+    pub claims: Vec<Claim>,
+    pub segment_image_id: Digest,
+}
+
+impl ClaimJournal {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + DOMAIN_SIZE + self.claims.len() * CLAIM_SIZE + DIGEST_SIZE);
+        out.push(JOURNAL_VERSION);
+        out.extend_from_slice(&<[u8; DOMAIN_SIZE]>::from(self.domain));
+
+        for claim in &self.claims {
+            out.extend_from_slice(&<[u8; CLAIM_SIZE]>::from(claim.clone()));
+        }
+
+        out.extend_from_slice(&self.segment_image_id);
+        out
+    }
+}
+
+impl TryFrom<&[u8]> for ClaimJournal {
+    type Error = JournalError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let (&version, rest) = buf.split_first().ok_or(JournalError::Length(0))?;
+
+        if version != JOURNAL_VERSION {
+            return Err(JournalError::Version(version));
+        }
+
+        if rest.len() < DOMAIN_SIZE + DIGEST_SIZE {
+            return Err(JournalError::Length(buf.len()));
+        }
+
+        let (domain_buf, rest) = rest.split_at(DOMAIN_SIZE);
+        let image_id_offset = rest.len() - DIGEST_SIZE;
+        let (claims_buf, image_id_buf) = rest.split_at(image_id_offset);
+        let chunks = claims_buf.chunks_exact(CLAIM_SIZE);
+
+        if !chunks.remainder().is_empty() {
+            return Err(JournalError::Length(buf.len()));
+        }
+
+        let claims = chunks
+            .map(Claim::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ClaimJournal {
+            domain: Domain::try_from(domain_buf)?,
+            claims,
+            segment_image_id: image_id_buf.try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::METER_UNITS;
+
+    fn domain() -> Domain {
+        Domain::new(31337, [1; 20], [2; 20])
+    }
+
+    fn claim(i: u8) -> Claim {
+        Claim {
+            pk: [i; 32],
+            start_seq: i as u32 + 1,
+            rounds: 3,
+            units_consumed: [i as u64 * 1000; METER_UNITS],
+            transcript_root: [i ^ 0xff; 32],
+        }
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        for count in 0..4u8 {
+            let journal = ClaimJournal {
+                domain: domain(),
+                claims: (0..count).map(claim).collect(),
+                segment_image_id: [9; 32],
+            };
+
+            let buf = journal.encode();
+            assert_eq!(buf.len(), 1 + DOMAIN_SIZE + count as usize * CLAIM_SIZE + DIGEST_SIZE);
+            assert_eq!(ClaimJournal::try_from(buf.as_slice()).unwrap(), journal);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_journal() {
+        let buf = ClaimJournal { domain: domain(), claims: vec![claim(1)], segment_image_id: [9; 32] }.encode();
+
+        let mut wrong_version = buf.clone();
+        wrong_version[0] = JOURNAL_VERSION + 1;
+        assert_eq!(ClaimJournal::try_from(wrong_version.as_slice()), Err(JournalError::Version(JOURNAL_VERSION + 1)));
+
+        assert_eq!(ClaimJournal::try_from(&buf[..buf.len() - 1]), Err(JournalError::Length(buf.len() - 1)));
+        assert_eq!(ClaimJournal::try_from(&buf[..0]), Err(JournalError::Length(0)));
+        assert_eq!(ClaimJournal::try_from(&buf[..DOMAIN_SIZE]), Err(JournalError::Length(DOMAIN_SIZE)));
+    }
+}
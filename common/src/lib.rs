@@ -1,21 +1,198 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+#[cfg(feature = "openai")]
+pub mod api;
+#[cfg(feature = "circuit")]
+pub mod circuit;
+pub mod journal;
+pub mod merkle;
 
 pub const SIGNATURE_SIZE: usize = 64;
 pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const DIGEST_SIZE: usize = 32;
+pub const ADDRESS_SIZE: usize = 20;
+
+/// Version of the signed message layout, part of every `Domain`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Metering unit, the index into per-unit usage, totals and prices.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MeterUnit {
+    InputTokens = 0,
+    OutputTokens = 1,
+    Images = 2,
+    AudioSeconds = 3,
+    Documents = 4,
+}
+
+pub const METER_UNITS: usize = 5;
+
+impl MeterUnit {
+    pub const ALL: [MeterUnit; METER_UNITS] = [
+        MeterUnit::InputTokens,
+        MeterUnit::OutputTokens,
+        MeterUnit::Images,
+        MeterUnit::AudioSeconds,
+        MeterUnit::Documents,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MeterUnit::InputTokens => "input_tokens",
+            MeterUnit::OutputTokens => "output_tokens",
+            MeterUnit::Images => "images",
+            MeterUnit::AudioSeconds => "audio_seconds",
+            MeterUnit::Documents => "documents",
+        }
+    }
+}
+
+/// Amount consumed per `MeterUnit` in a single round.
+pub type Usage = [u32; METER_UNITS];
+
+pub const REQUEST_MSG_SIZE: usize = 4 + DIGEST_SIZE;
 
-pub const CLAIM_SIZE: usize = PUBLIC_KEY_SIZE + 4 + 4 + 8;
+pub const CONFIRM_MSG_SIZE: usize = 4 + DIGEST_SIZE + 4 * METER_UNITS;
+
+pub const CLAIM_SIZE: usize = PUBLIC_KEY_SIZE + 4 + 4 + 8 * METER_UNITS + DIGEST_SIZE;
+
+pub const DOMAIN_SIZE: usize = 4 + 8 + ADDRESS_SIZE + ADDRESS_SIZE;
 
 pub type PublicKey = [u8; PUBLIC_KEY_SIZE];
+pub type Digest = [u8; DIGEST_SIZE];
+
+/// Max distance between a new request seq and the lowest unconfirmed round of a client.
+pub const SEQ_WINDOW: u32 = 16;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RequestKind {
+    Completion = 0,
+    Chat = 1,
+    Embedding = 2,
+}
+
+/// Canonical form of what the client asked for, bound to the round through `RequestMsg::req_hash`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequestContent {
+    pub kind: RequestKind,
+    pub model: String,
+    // `api::canonical_json` of the prompt, messages or embedding input
+    pub input: String,
+    pub max_tokens: Option<u32>,
+}
+
+impl RequestContent {
+    pub fn digest(&self) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update([self.kind as u8]);
+        hasher.update((self.model.len() as u32).to_be_bytes());
+        hasher.update(self.model.as_bytes());
+        hasher.update((self.input.len() as u32).to_be_bytes());
+        hasher.update(self.input.as_bytes());
+
+        match self.max_tokens {
+            None => hasher.update([0]),
+            Some(max_tokens) => {
+                hasher.update([1]);
+                hasher.update(max_tokens.to_be_bytes());
+            }
+        }
+
+        hasher.finalize().into()
+    }
+}
+
+/// What a client's signatures are bound to, so they can't be replayed against another provider,
+/// contract deployment or chain where the same client key has a record.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Domain {
+    pub version: u32,
+    pub chain_id: u64,
+    // Deopenchat contract
+    pub contract: [u8; ADDRESS_SIZE],
+    pub provider: [u8; ADDRESS_SIZE],
+}
+
+impl Domain {
+    pub fn new(chain_id: u64, contract: [u8; ADDRESS_SIZE], provider: [u8; ADDRESS_SIZE]) -> Self {
+        Domain {
+            version: PROTOCOL_VERSION,
+            chain_id,
+            contract,
+            provider,
+        }
+    }
+
+    /// Bytes a client signs for a request.
+    pub fn request_payload(&self, msg: RequestMsg) -> [u8; DOMAIN_SIZE + REQUEST_MSG_SIZE] {
+        let mut out = [0u8; DOMAIN_SIZE + REQUEST_MSG_SIZE];
+        out[..DOMAIN_SIZE].copy_from_slice(&<[u8; DOMAIN_SIZE]>::from(*self));
+        out[DOMAIN_SIZE..].copy_from_slice(&<[u8; REQUEST_MSG_SIZE]>::from(msg));
+        out
+    }
+
+    /// Bytes a client signs for a confirm.
+    pub fn confirm_payload(&self, msg: ConfirmMsg) -> [u8; DOMAIN_SIZE + CONFIRM_MSG_SIZE] {
+        let mut out = [0u8; DOMAIN_SIZE + CONFIRM_MSG_SIZE];
+        out[..DOMAIN_SIZE].copy_from_slice(&<[u8; DOMAIN_SIZE]>::from(*self));
+        out[DOMAIN_SIZE..].copy_from_slice(&<[u8; CONFIRM_MSG_SIZE]>::from(msg));
+        out
+    }
+}
+
+impl From<Domain> for [u8; DOMAIN_SIZE] {
+    fn from(d: Domain) -> Self {
+        let mut out = [0u8; DOMAIN_SIZE];
+        let (version, buff) = out.split_at_mut(4);
+        version.copy_from_slice(&d.version.to_be_bytes());
+
+        let (chain_id, buff) = buff.split_at_mut(8);
+        chain_id.copy_from_slice(&d.chain_id.to_be_bytes());
+
+        let (contract, provider) = buff.split_at_mut(ADDRESS_SIZE);
+        contract.copy_from_slice(&d.contract);
+        provider.copy_from_slice(&d.provider);
+        out
+    }
+}
+
+impl TryFrom<&[u8]> for Domain {
+    type Error = journal::JournalError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        if buf.len() != DOMAIN_SIZE {
+            return Err(journal::JournalError::Length(buf.len()));
+        }
+
+        let (version, buff) = buf.split_at(4);
+        let (chain_id, buff) = buff.split_at(8);
+        let (contract, provider) = buff.split_at(ADDRESS_SIZE);
+
+        Ok(Domain {
+            version: u32::from_be_bytes(version.try_into().unwrap()),
+            chain_id: u64::from_be_bytes(chain_id.try_into().unwrap()),
+            contract: contract.try_into().unwrap(),
+            provider: provider.try_into().unwrap(),
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 pub struct RequestMsg {
     pub seq: u32,
+    pub req_hash: Digest,
 }
 
-impl From<RequestMsg> for [u8; 4] {
+impl From<RequestMsg> for [u8; REQUEST_MSG_SIZE] {
     fn from(m: RequestMsg) -> Self {
-        m.seq.to_be_bytes()
+        let mut out = [0u8; REQUEST_MSG_SIZE];
+        out[..4].copy_from_slice(&m.seq.to_be_bytes());
+        out[4..].copy_from_slice(&m.req_hash);
+        out
     }
 }
 
@@ -25,19 +202,34 @@ pub struct Request {
     pub signature: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
 pub struct ConfirmMsg {
     pub seq: u32,
-    pub input_tokens: u32,
-    pub resp_tokens: u32
+    // digest of the response the client received
+    pub resp_hash: Digest,
+    pub usage: Usage,
+}
+
+impl ConfirmMsg {
+    /// Confirm of a round that failed or was abandoned, it bills nothing.
+    pub fn void(seq: u32) -> Self {
+        ConfirmMsg {
+            seq,
+            resp_hash: [0; DIGEST_SIZE],
+            usage: [0; METER_UNITS],
+        }
+    }
 }
 
-impl From<ConfirmMsg> for [u8; 12] {
+impl From<ConfirmMsg> for [u8; CONFIRM_MSG_SIZE] {
     fn from(m: ConfirmMsg) -> Self {
-        let mut out = [0u8; 12];
+        let mut out = [0u8; CONFIRM_MSG_SIZE];
         out[..4].copy_from_slice(&m.seq.to_be_bytes());
-        out[4..8].copy_from_slice(&m.input_tokens.to_be_bytes());
-        out[8..].copy_from_slice(&m.resp_tokens.to_be_bytes());
+        out[4..4 + DIGEST_SIZE].copy_from_slice(&m.resp_hash);
+
+        for (buf, amount) in out[4 + DIGEST_SIZE..].chunks_exact_mut(4).zip(m.usage) {
+            buf.copy_from_slice(&amount.to_be_bytes());
+        }
         out
     }
 }
@@ -48,23 +240,47 @@ pub struct Confirm {
     pub signature: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Round {
     pub request: Request,
-    pub confirm: Confirm,
+    // none for rounds the client never confirmed, they count as void
+    pub confirm: Option<Confirm>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Input {
-    pub rounds: HashMap<PublicKey, Vec<Round>>
+    // the rounds were signed for
+    pub domain: Domain,
+    // ordered, so the journal does not depend on hash iteration order
+    pub rounds: BTreeMap<PublicKey, Vec<Round>>
+}
+
+/// Journal entry of the rounds guest, one client's proven rounds.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub domain: Domain,
+    pub pk: PublicKey,
+    pub start_seq: u32,
+    pub units_consumed: [u64; METER_UNITS],
+    // `merkle::leaf` of every round, the aggregation guest builds the transcript root over them
+    pub leaves: Vec<Digest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AggregateInput {
+    pub segment_image_id: [u32; 8],
+    // journals of the composed segment receipts
+    pub segments: Vec<Vec<Segment>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Claim {
     pub pk: PublicKey,
     pub start_seq: u32,
     pub rounds: u32,
-    pub tokens_consumed: u64,
+    pub units_consumed: [u64; METER_UNITS],
+    // merkle root over the claimed rounds, see `merkle::leaf`
+    pub transcript_root: Digest,
 }
 
 impl From<Claim> for [u8; CLAIM_SIZE] {
@@ -76,14 +292,49 @@ impl From<Claim> for [u8; CLAIM_SIZE] {
         let (start_seq, buff) = buff.split_at_mut(4);
         start_seq.copy_from_slice(&claim.start_seq.to_be_bytes());
 
-        let (rounds, tokens_consumed) = buff.split_at_mut(4);
+        let (rounds, buff) = buff.split_at_mut(4);
         rounds.copy_from_slice(&claim.rounds.to_be_bytes());
 
-        tokens_consumed.copy_from_slice(&claim.tokens_consumed.to_be_bytes());
+        let (units_consumed, transcript_root) = buff.split_at_mut(8 * METER_UNITS);
+
+        for (buf, amount) in units_consumed.chunks_exact_mut(8).zip(claim.units_consumed) {
+            buf.copy_from_slice(&amount.to_be_bytes());
+        }
+
+        transcript_root.copy_from_slice(&claim.transcript_root);
         out
     }
 }
 
+impl TryFrom<&[u8]> for Claim {
+    type Error = journal::JournalError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        if buf.len() != CLAIM_SIZE {
+            return Err(journal::JournalError::Length(buf.len()));
+        }
+
+        let (pk, buff) = buf.split_at(PUBLIC_KEY_SIZE);
+        let (start_seq, buff) = buff.split_at(4);
+        let (rounds, buff) = buff.split_at(4);
+        let (units_buf, transcript_root) = buff.split_at(8 * METER_UNITS);
+
+        let mut units_consumed = [0u64; METER_UNITS];
+
+        for (amount, buf) in units_consumed.iter_mut().zip(units_buf.chunks_exact(8)) {
+            *amount = u64::from_be_bytes(buf.try_into().unwrap());
+        }
+
+        Ok(Claim {
+            pk: pk.try_into().unwrap(),
+            start_seq: u32::from_be_bytes(start_seq.try_into().unwrap()),
+            rounds: u32::from_be_bytes(rounds.try_into().unwrap()),
+            units_consumed,
+            transcript_root: transcript_root.try_into().unwrap(),
+        })
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CompletionsReq<Req> {
     pub pk: PublicKey,
@@ -91,6 +342,16 @@ pub struct CompletionsReq<Req> {
     pub request: Request,
 }
 
+impl<Req> CompletionsReq<Req> {
+    pub fn map<R>(self, f: impl FnOnce(Req) -> R) -> CompletionsReq<R> {
+        CompletionsReq {
+            pk: self.pk,
+            raw_req: f(self.raw_req),
+            request: self.request,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CompletionsResp<Resp> {
     pub raw_response: Resp,
@@ -100,4 +361,20 @@ pub struct CompletionsResp<Resp> {
 pub struct ConfirmReq {
     pub pk: PublicKey,
     pub confirm: Confirm
+}
+
+/// Where a client stands with the gateway, a bridge resumes from it.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct SeqStatus {
+    // highest requested seq
+    pub seq: u32,
+    // every round up to here is completed, new rounds are accepted within `SEQ_WINDOW` of it
+    pub confirmed_seq: u32,
+}
+
+/// Client gives up on a round, `confirm` must sign `ConfirmMsg::void`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AbandonReq<Req> {
+    pub req: CompletionsReq<Req>,
+    pub confirm: Confirm
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use crate::{ConfirmMsg, Digest, RequestMsg, CONFIRM_MSG_SIZE, REQUEST_MSG_SIZE};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Leaf of a client's transcript, commits to the request, the response and the usage of one round.
+pub fn leaf(request: RequestMsg, confirm: ConfirmMsg) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(<[u8; REQUEST_MSG_SIZE]>::from(request));
+    hasher.update(<[u8; CONFIRM_MSG_SIZE]>::from(confirm));
+    hasher.finalize().into()
+}
+
+fn node(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// an odd node at the end of a level is carried up unchanged
+fn next_level(level: &[Digest]) -> Vec<Digest> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn root(leaves: &[Digest]) -> Digest {
+    if leaves.is_empty() {
+        return [0u8; 32];
+    }
+
+    let mut level = leaves.to_vec();
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub leaf_count: u32,
+    pub siblings: Vec<Digest>,
+}
+
+impl MerkleProof {
+    pub fn new(leaves: &[Digest], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+
+        let mut siblings = Vec::new();
+        let mut level = leaves.to_vec();
+        let mut idx = index;
+
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(idx ^ 1) {
+                siblings.push(*sibling);
+            }
+
+            level = next_level(&level);
+            idx /= 2;
+        }
+
+        Some(MerkleProof {
+            index: index as u32,
+            leaf_count: leaves.len() as u32,
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, root: &Digest, leaf: &Digest) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+
+        let mut hash = *leaf;
+        let mut idx = self.index;
+        let mut width = self.leaf_count;
+        let mut siblings = self.siblings.iter();
+
+        while width > 1 {
+            if (idx ^ 1) < width {
+                let sibling = match siblings.next() {
+                    Some(s) => s,
+                    None => return false,
+                };
+
+                hash = if idx & 1 == 0 {
+                    node(&hash, sibling)
+                } else {
+                    node(sibling, &hash)
+                };
+            }
+
+            idx /= 2;
+            width = width.div_ceil(2);
+        }
+
+        siblings.next().is_none() && hash == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proof_round_trip() {
+        for count in 1..=9u8 {
+            let leaves = (0..count).map(|i| [i; 32]).collect::<Vec<_>>();
+            let root = root(&leaves);
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, i).unwrap();
+                assert!(proof.verify(&root, leaf));
+                assert!(!proof.verify(&root, &[0xff; 32]));
+            }
+        }
+    }
+}
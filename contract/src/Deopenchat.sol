@@ -15,14 +15,21 @@ interface IRiscZeroVerifier {
 }
 
 contract Deopenchat {
+    // input tokens, output tokens, images, audio seconds, documents
+    uint constant METER_UNITS = 5;
+    uint constant UNIT_INPUT_TOKENS = 0;
+    uint constant UNIT_OUTPUT_TOKENS = 1;
+
     struct Record {
         uint32 seq;
-        uint64 remainingTokens;
+        // prepaid wei, charged per unit consumed at the provider's prices
+        uint256 balance;
     }
 
     struct Provider {
         address providerAddress;
-        uint32 costPerKTokens;
+        // wei per 1000 units, indexed by meter unit
+        uint32[METER_UNITS] costPerKUnits;
         string endpoint;
         string model;
     }
@@ -32,23 +39,36 @@ contract Deopenchat {
     mapping(address => Provider) providerMapping;
     // provider -> client -> record
     mapping(address => mapping(bytes32 => Record)) records;
+    // provider -> sum of its clients' balances, claims are priced at the current costs
+    // so those can't go up while clients have prepaid at the old ones
+    mapping(address => uint256) openBalances;
 
+    // aggregation guest, verified on claim
     bytes32 imageId;
+    // rounds guest whose receipts the aggregation guest composes, committed at the end of the journal
+    bytes32 segmentImageId;
     address IRiscZeroContract;
 
-    constructor(bytes32 id, address risc0Addr) {
+    constructor(bytes32 id, bytes32 segmentId, address risc0Addr) {
         imageId = id;
+        segmentImageId = segmentId;
         IRiscZeroContract = risc0Addr;
     }
 
     function providerRegister(
-        uint32 ktokensCost,
+        uint32[METER_UNITS] calldata costPerKUnits,
         string calldata endpoint,
         string calldata model
     ) public {
+        if (openBalances[msg.sender] > 0) {
+            for (uint u = 0; u < METER_UNITS; u++) {
+                require(costPerKUnits[u] <= providerMapping[msg.sender].costPerKUnits[u], "price increase with open balances");
+            }
+        }
+
         Provider memory p = Provider ({
             providerAddress: msg.sender,
-            costPerKTokens: ktokensCost,
+            costPerKUnits: costPerKUnits,
             endpoint: endpoint,
             model: model
         });
@@ -65,6 +85,10 @@ contract Deopenchat {
         return imageId;
     }
 
+    function getSegmentImageId() view public returns(bytes32) {
+        return segmentImageId;
+    }
+
     function getAllProviders() view public returns(Provider[] memory) {
         Provider[] memory ret = new Provider[](providers.length);
 
@@ -79,65 +103,137 @@ contract Deopenchat {
         return records[provider][clientPk];
     }
 
-    function fethTokens(address provider, uint32 ktokens, bytes32 clientPk) payable public {
-        uint32 costPerKt = providerMapping[provider].costPerKTokens;
-        require(costPerKt > 0);
+    function fethTokens(address provider, uint32 inputKTokens, uint32 outputKTokens, bytes32 clientPk) payable public {
+        uint256 costPerInputKt = providerMapping[provider].costPerKUnits[UNIT_INPUT_TOKENS];
+        uint256 costPerOutputKt = providerMapping[provider].costPerKUnits[UNIT_OUTPUT_TOKENS];
+        require(costPerInputKt > 0 || costPerOutputKt > 0);
 
-        uint32 needCost = ktokens * costPerKt;
+        uint256 needCost = uint256(inputKTokens) * costPerInputKt + uint256(outputKTokens) * costPerOutputKt;
         require(needCost <= msg.value, "not enough amount!");
 
         payable(address(this)).transfer(msg.value);
         // todo payable(msg.sender).transfer()
-        records[provider][clientPk].remainingTokens += ktokens * 1000;
+        records[provider][clientPk].balance += msg.value;
+        openBalances[provider] += msg.value;
     }
 
     struct Claim {
         bytes32 clientPk;
         uint32 seq;
         uint32 rounds;
-        uint64 numberTokensConsumed;
+        uint64[METER_UNITS] unitsConsumed;
+        // merkle root over the request, response and usage of each claimed round
+        bytes32 transcriptRoot;
     }
 
-    // (clientPk + seq + rounds + numberTokensConsumed)
-    uint constant CLAIM_SIZE = 32 + 4 + 4 + 8;
+    // (clientPk + seq + rounds + unitsConsumed + transcriptRoot)
+    uint constant CLAIM_SIZE = 32 + 4 + 4 + 8 * METER_UNITS + 32;
+
+    // leading byte of the journal, see common::journal
+    uint8 constant JOURNAL_VERSION = 2;
+
+    // version of the messages clients sign, see common::Domain
+    uint32 constant PROTOCOL_VERSION = 1;
+
+    // (version + chainId + contract + provider)
+    uint constant DOMAIN_SIZE = 4 + 8 + 20 + 20;
+
+    event TranscriptCommitted(address indexed provider, bytes32 indexed clientPk, uint32 seq, uint32 rounds, bytes32 transcriptRoot);
+
+    function unitsCost(address provider, uint64[METER_UNITS] calldata unitsConsumed) view internal returns(uint256) {
+        uint256 cost = 0;
+
+        for (uint u = 0; u < METER_UNITS; u++) {
+            cost += uint256(unitsConsumed[u]) * providerMapping[provider].costPerKUnits[u];
+        }
+
+        return cost / 1000;
+    }
 
     function verifyTest(bytes calldata seal, bytes calldata journal) view public {
         IRiscZeroVerifier(IRiscZeroContract).verify(seal, imageId, sha256(journal));
     }
 
     function claim(Claim[] calldata claimList, bytes calldata seal) payable public {
-        bytes memory journal = new bytes(CLAIM_SIZE * claimList.length);
-        uint64 totalTokensUsage = 0;
+        bytes memory journal = new bytes(1 + DOMAIN_SIZE + CLAIM_SIZE * claimList.length + 32);
+        journal[0] = bytes1(JOURNAL_VERSION);
+        uint256 totalCost = 0;
+
+        {
+            // the rounds must have been signed for this provider on this deployment
+            bytes4 version = bytes4(PROTOCOL_VERSION);
+            bytes8 chainId = bytes8(uint64(block.chainid));
+            bytes20 contractAddr = bytes20(address(this));
+            bytes20 provider = bytes20(msg.sender);
+
+            uint versionoffset = 32 + 1;
+            uint chainidoffset = versionoffset + 4;
+            uint contractoffset = chainidoffset + 8;
+            uint provideroffset = contractoffset + 20;
+
+            assembly {
+                mstore(add(journal, versionoffset), version)
+                mstore(add(journal, chainidoffset), chainId)
+                mstore(add(journal, contractoffset), contractAddr)
+                mstore(add(journal, provideroffset), provider)
+            }
+        }
 
         for (uint32 i = 0; i < claimList.length; i++) {
             Claim calldata c = claimList[i];
             bytes32 clientPk = c.clientPk;
             bytes4 seq = bytes4(c.seq);
             bytes4 rounds = bytes4(c.rounds);
-            bytes8 numberTokensConsumed = bytes8(c.numberTokensConsumed);
 
-            uint pkoffset = 32 + CLAIM_SIZE * i;
+            uint pkoffset = 32 + 1 + DOMAIN_SIZE + CLAIM_SIZE * i;
             uint seqoffset = pkoffset + 32;
             uint roundsoffset = seqoffset + 4;
-            uint numberTokensConsumedoffset = roundsoffset + 4;
+            uint unitsoffset = roundsoffset + 4;
 
             assembly {
                 mstore(add(journal, pkoffset), clientPk)
                 mstore(add(journal, seqoffset), seq)
                 mstore(add(journal, roundsoffset), rounds)
-                mstore(add(journal, numberTokensConsumedoffset), numberTokensConsumed)
             }
 
-            require(records[msg.sender][c.clientPk].remainingTokens >= c.numberTokensConsumed, "no enough tokens");
+            for (uint u = 0; u < METER_UNITS; u++) {
+                bytes8 unitConsumed = bytes8(c.unitsConsumed[u]);
+                uint unitoffset = unitsoffset + 8 * u;
+
+                assembly {
+                    mstore(add(journal, unitoffset), unitConsumed)
+                }
+            }
+
+            bytes32 transcriptRoot = c.transcriptRoot;
+            uint rootoffset = unitsoffset + 8 * METER_UNITS;
+
+            assembly {
+                mstore(add(journal, rootoffset), transcriptRoot)
+            }
+
+            uint256 cost = unitsCost(msg.sender, c.unitsConsumed);
+
+            require(records[msg.sender][c.clientPk].balance >= cost, "no enough balance");
             require(records[msg.sender][c.clientPk].seq + 1 == c.seq);
 
-            records[msg.sender][c.clientPk].remainingTokens -= c.numberTokensConsumed;
+            records[msg.sender][c.clientPk].balance -= cost;
+            openBalances[msg.sender] -= cost;
             records[msg.sender][c.clientPk].seq += c.rounds;
-            totalTokensUsage += c.numberTokensConsumed;
+            totalCost += cost;
+
+            emit TranscriptCommitted(msg.sender, c.clientPk, c.seq, c.rounds, c.transcriptRoot);
+        }
+
+        bytes32 segmentId = segmentImageId;
+        uint segmentoffset = 32 + 1 + DOMAIN_SIZE + CLAIM_SIZE * claimList.length;
+
+        assembly {
+            mstore(add(journal, segmentoffset), segmentId)
         }
 
         IRiscZeroVerifier(IRiscZeroContract).verify(seal, imageId, sha256(journal));
-        payable(msg.sender).transfer(totalTokensUsage / 1000 * providerMapping[msg.sender].costPerKTokens);
+        payable(msg.sender).transfer(totalCost);
     }
 
     fallback() external payable {}
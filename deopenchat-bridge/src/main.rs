@@ -1,130 +1,422 @@
 use alloy::hex::FromHex;
 use alloy::network::EthereumWallet;
 use alloy::primitives::{Address, FixedBytes, U256};
-use alloy::providers::{ProviderBuilder};
+use alloy::providers::{Provider, ProviderBuilder};
 use alloy::signers::local::PrivateKeySigner;
 use alloy::{hex, sol};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
+use async_openai::types::{CreateChatCompletionRequest, CreateCompletionRequest, CreateEmbeddingRequest};
 use axum::body::Body;
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::response::Response;
-use axum::routing::get;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use axum::{Json, Router};
 use clap::{Parser, Subcommand};
-use common::{CompletionsReq, CompletionsResp, Confirm, ConfirmMsg, ConfirmReq, Request, RequestMsg};
+use common::api::{ApiRequest, ApiResponse, ChatAggregator, CompletionAggregator, StreamAggregator};
+use common::{AbandonReq, CompletionsReq, CompletionsResp, Confirm, ConfirmMsg, ConfirmReq, Domain, MeterUnit, Request, RequestMsg, SeqStatus, Usage, METER_UNITS, SEQ_WINDOW};
 use ed25519_dalek::{SecretKey, Signer, SigningKey};
-use futures_util::TryFutureExt;
+use eventsource_stream::Eventsource;
+use futures_util::{StreamExt, TryFutureExt};
 use log::{error, info, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use prettytable::{row, Table};
 use reqwest::Url;
+use std::collections::BTreeSet;
+use std::convert::Infallible;
 use std::future::IntoFuture;
 use std::net::SocketAddr;
 use std::process::ExitCode;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
 
 sol!{
     #[sol(rpc)]
     "../contract/src/Deopenchat.sol"
 }
 
+enum Responder {
+    Unary(oneshot::Sender<ApiResponse>),
+    Stream(mpsc::Sender<Event>),
+}
+
 struct Context {
-    task_sender: mpsc::Sender<(async_openai::types::CreateCompletionRequest, tokio::sync::oneshot::Sender<async_openai::types::CreateCompletionResponse>)>,
+    task_sender: mpsc::Sender<(ApiRequest, Responder)>,
 }
 
-async fn completions_handler(
-    client: reqwest::Client,
-    endpoint: Url,
-    mut seq: u32,
-    sk: SigningKey,
-    mut task_recv: mpsc::Receiver<(async_openai::types::CreateCompletionRequest, tokio::sync::oneshot::Sender<async_openai::types::CreateCompletionResponse>)>
-) -> Result<()> {
-    let completions_url = endpoint.join("/v1/completions")?;
+// a confirm or abandon that never lands leaves the round open on the gateway
+const SEND_ATTEMPTS: u32 = 5;
+// how often a full seq window asks the gateway whether it closed rounds on its own
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
-    while let Some((req, tx)) = task_recv.recv().await {
-        let msg = RequestMsg {
-            seq
-        };
+async fn send_with_retry(build: impl Fn() -> reqwest::RequestBuilder) -> Result<()> {
+    let mut attempt = 1;
 
-        let signature = sk.sign(&<[u8; 4]>::from(msg));
+    loop {
+        let res = build().send()
+            .await
+            .and_then(|resp| resp.error_for_status());
 
-        let req = CompletionsReq {
-            pk: sk.verifying_key().to_bytes(),
-            raw_req: req,
-            request: Request {
-                msg,
-                signature: signature.to_vec()
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= SEND_ATTEMPTS => return Err(e.into()),
+            Err(e) => {
+                error!("send failed, attempt: {}: {:?}", attempt, e);
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
             }
-        };
+        }
+    }
+}
 
-        let resp = client.get(completions_url.clone())
-            .json(&req)
-            .send()
-            .await?;
+async fn stream_request<A: StreamAggregator>(
+    req: reqwest::RequestBuilder,
+    event_tx: &mpsc::Sender<Event>,
+    mut aggregator: A
+) -> Result<ApiResponse> {
+    let resp = req.send().await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await?;
+        error!("completions error: {}, body: {}", status, body);
+        return Err(anyhow!("completions failed"));
+    }
 
-        let resp_body = resp.bytes().await?;
-        let resp: CompletionsResp<async_openai::types::CreateCompletionResponse> = match serde_json::from_slice(&resp_body) {
-            Err(e) => {
-                error!("completions error: {:?}, body: {}", e, String::from_utf8_lossy(&resp_body));
-                return Err(anyhow!("completions failed"));
-            }
-            Ok(resp) => resp,
-        };
+    let mut events = resp.bytes_stream().eventsource();
+
+    while let Some(event) = events.next().await {
+        let event = event?;
+        ensure!(event.event != "error", "completions stream error: {}", event.data);
+
+        if event.data == "[DONE]" {
+            return aggregator.finish().ok_or_else(|| anyhow!("empty stream"));
+        }
+
+        let chunk: A::Chunk = serde_json::from_str(&event.data)?;
+        let _ = event_tx.send(Event::default().json_data(&chunk)?).await;
+        aggregator.push(chunk);
+    }
+
+    Err(anyhow!("completions stream closed before [DONE]"))
+}
+
+/// Lowest seq below which every round is confirmed, the gateway accepts
+/// new rounds only within `SEQ_WINDOW` of it.
+struct SeqWindow {
+    confirmed_seq: u32,
+    done: BTreeSet<u32>,
+    confirmed_tx: watch::Sender<u32>,
+}
+
+impl SeqWindow {
+    fn complete(&mut self, seq: u32) {
+        if seq > self.confirmed_seq {
+            self.done.insert(seq);
+            self.advance();
+        }
+    }
+
+    // rounds the gateway completed without us, left open by a previous run or voided at their deadline
+    fn sync(&mut self, confirmed_seq: u32) {
+        if confirmed_seq > self.confirmed_seq {
+            self.confirmed_seq = confirmed_seq;
+            self.done.retain(|&seq| seq > confirmed_seq);
+            self.advance();
+        }
+    }
+
+    fn advance(&mut self) {
+        while self.done.remove(&(self.confirmed_seq + 1)) {
+            self.confirmed_seq += 1;
+        }
+
+        self.confirmed_tx.send_replace(self.confirmed_seq);
+    }
+}
+
+fn sign_request(sk: &SigningKey, domain: &Domain, seq: u32, raw_req: &ApiRequest) -> Result<Request> {
+    let msg = RequestMsg {
+        seq,
+        req_hash: raw_req.content()?.digest()
+    };
+
+    let signature = sk.sign(&domain.request_payload(msg));
+
+    Ok(Request {
+        msg,
+        signature: signature.to_vec()
+    })
+}
 
-        let usage = resp.raw_response.usage.clone().ok_or_else(|| anyhow!("missing usage"))?;
-        // todo verify number of tokens
+/// Runs a round up to the response, returns the confirm that bills it.
+async fn round(
+    client: &reqwest::Client,
+    endpoint: &Url,
+    sk: &SigningKey,
+    domain: &Domain,
+    raw_req: &ApiRequest,
+    request: Request,
+    responder: Responder
+) -> Result<ConfirmReq> {
+    let seq = request.msg.seq;
+    let pk = sk.verifying_key().to_bytes();
+
+    let builder = match raw_req {
+        ApiRequest::Completion(raw_req) => {
+            client.get(endpoint.join("/v1/completions")?)
+                .json(&CompletionsReq { pk, raw_req, request })
+        }
+        ApiRequest::Chat(raw_req) => {
+            client.post(endpoint.join("/v1/chat/completions")?)
+                .json(&CompletionsReq { pk, raw_req, request })
+        }
+        ApiRequest::Embedding(raw_req) => {
+            client.post(endpoint.join("/v1/embeddings")?)
+                .json(&CompletionsReq { pk, raw_req, request })
+        }
+    };
 
-        let _ = tx.send(resp.raw_response);
+    let raw_response = match responder {
+        Responder::Unary(tx) => {
+            let resp = builder.send().await?;
+
+            let resp_body = resp.bytes().await?;
+            let resp: CompletionsResp<ApiResponse> = match serde_json::from_slice(&resp_body) {
+                Err(e) => {
+                    error!("completions error: {:?}, body: {}", e, String::from_utf8_lossy(&resp_body));
+                    return Err(anyhow!("completions failed"));
+                }
+                Ok(resp) => resp,
+            };
+
+            let _ = tx.send(resp.raw_response.clone());
+            resp.raw_response
+        }
+        Responder::Stream(event_tx) => {
+            let resp = match raw_req {
+                ApiRequest::Completion(_) => stream_request(builder, &event_tx, CompletionAggregator::default()).await?,
+                ApiRequest::Chat(_) => stream_request(builder, &event_tx, ChatAggregator::default()).await?,
+                ApiRequest::Embedding(_) => return Err(anyhow!("embeddings can not be streamed")),
+            };
+
+            let _ = event_tx.send(Event::default().data("[DONE]")).await;
+            resp
+        }
+    };
 
-        let msg = ConfirmMsg {
-            seq,
-            input_tokens: usage.prompt_tokens,
-            resp_tokens: usage.completion_tokens
-        };
+    let usage = raw_response.usage().ok_or_else(|| anyhow!("missing usage"))?;
+    // todo verify number of tokens
 
-        let signature = sk.sign(&<[u8; 12]>::from(msg));
+    let mut units: Usage = [0; METER_UNITS];
+    units[MeterUnit::InputTokens as usize] = usage.prompt_tokens;
+    units[MeterUnit::OutputTokens as usize] = usage.completion_tokens;
 
-        let req = ConfirmReq {
+    let msg = ConfirmMsg {
+        seq,
+        resp_hash: raw_response.digest()?,
+        usage: units
+    };
+
+    let signature = sk.sign(&domain.confirm_payload(msg));
+
+    Ok(ConfirmReq {
+        pk,
+        confirm: Confirm {
+            msg,
+            signature: signature.to_vec()
+        }
+    })
+}
+
+async fn confirm(client: &reqwest::Client, endpoint: &Url, req: &ConfirmReq) -> Result<()> {
+    let completions_confirm_url = endpoint.join("/v1/completions/confirm")?;
+    send_with_retry(|| client.post(completions_confirm_url.clone()).json(req)).await
+}
+
+/// Closes a round that got no response with zero usage so the gateway does not wait for it.
+async fn abandon(
+    client: &reqwest::Client,
+    endpoint: &Url,
+    sk: &SigningKey,
+    domain: &Domain,
+    raw_req: ApiRequest,
+    request: Request
+) -> Result<()> {
+    let msg = ConfirmMsg::void(request.msg.seq);
+    let signature = sk.sign(&domain.confirm_payload(msg));
+
+    let req = AbandonReq {
+        req: CompletionsReq {
             pk: sk.verifying_key().to_bytes(),
-            confirm: Confirm {
-                msg,
-                signature: signature.to_vec()
+            raw_req,
+            request
+        },
+        confirm: Confirm {
+            msg,
+            signature: signature.to_vec()
+        }
+    };
+
+    let completions_abandon_url = endpoint.join("/v1/completions/abandon")?;
+    send_with_retry(|| client.post(completions_abandon_url.clone()).json(&req)).await
+}
+
+async fn seq_status(client: &reqwest::Client, endpoint: &Url, pk: &[u8; 32]) -> Result<SeqStatus> {
+    let query_seq = endpoint
+        .join("/v1/completions/seq/")?
+        .join(&hex::encode(pk))?;
+
+    let status = client.get(query_seq)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(status)
+}
+
+/// Abandons the seqs a previous run skipped past without the gateway ever seeing them.
+async fn close_holes(client: &reqwest::Client, endpoint: &Url, sk: &SigningKey, domain: &Domain) -> Result<()> {
+    let query_holes = endpoint
+        .join("/v1/completions/holes/")?
+        .join(&hex::encode(sk.verifying_key().to_bytes()))?;
+
+    let holes: Vec<u32> = client.get(query_holes)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    for seq in holes {
+        // the original request is gone, any content signed for the seq closes it with zero usage
+        let raw_req = ApiRequest::Embedding(CreateEmbeddingRequest::default());
+        let request = sign_request(sk, domain, seq, &raw_req)?;
+
+        abandon(client, endpoint, sk, domain, raw_req, request).await?;
+        info!("abandoned seq {} left open by a previous run", seq);
+    }
+    Ok(())
+}
+
+async fn completions_handler(
+    client: reqwest::Client,
+    endpoint: Url,
+    status: SeqStatus,
+    sk: SigningKey,
+    domain: Domain,
+    max_inflight: u32,
+    mut task_recv: mpsc::Receiver<(ApiRequest, Responder)>
+) -> Result<()> {
+    let mut seq = status.seq + 1;
+    let pk = sk.verifying_key().to_bytes();
+    let (confirmed_tx, mut confirmed_rx) = watch::channel(status.confirmed_seq);
+
+    // rounds a previous run left open count against the gateway's window until it closes them
+    let window = Arc::new(std::sync::Mutex::new(SeqWindow {
+        confirmed_seq: status.confirmed_seq,
+        done: BTreeSet::new(),
+        confirmed_tx,
+    }));
+
+    let mut rounds = JoinSet::new();
+
+    while let Some((raw_req, responder)) = task_recv.recv().await {
+        loop {
+            tokio::select! {
+                res = async { confirmed_rx.wait_for(|&confirmed_seq| seq <= confirmed_seq + max_inflight).await.map(|_| ()) } => {
+                    res?;
+                    break;
+                }
+                Some(res) = rounds.join_next() => res??,
+                _ = tokio::time::sleep(WINDOW_POLL_INTERVAL) => {
+                    match seq_status(&client, &endpoint, &pk).await {
+                        Ok(status) => window.lock().unwrap().sync(status.confirmed_seq),
+                        Err(e) => error!("query seq status failed: {:?}", e),
+                    }
+                }
             }
-        };
+        }
 
-        let completions_confirm_url =  endpoint.join("/v1/completions/confirm")?;
+        let request = sign_request(&sk, &domain, seq, &raw_req)?;
+
+        let client = client.clone();
+        let endpoint = endpoint.clone();
+        let sk = sk.clone();
+        let window = window.clone();
+        let curr_seq = seq;
+
+        rounds.spawn(async move {
+            match round(&client, &endpoint, &sk, &domain, &raw_req, request.clone(), responder).await {
+                // an answered round is only closed by its confirm, the gateway voids it at the deadline otherwise
+                Ok(req) => {
+                    if let Err(e) = confirm(&client, &endpoint, &req).await {
+                        error!("confirm round {} failed: {:?}", curr_seq, e);
+                    }
+                }
+                Err(e) => {
+                    error!("round {} failed: {:?}", curr_seq, e);
+
+                    // a seq left open here is closed by `close_holes` on the next start
+                    if let Err(e) = abandon(&client, &endpoint, &sk, &domain, raw_req, request).await {
+                        error!("abandon round {} failed: {:?}", curr_seq, e);
+                    }
+                }
+            }
 
-        client.post(completions_confirm_url)
-            .json(&req)
-            .send()
-            .await?;
+            window.lock().unwrap().complete(curr_seq);
+            Result::<_, anyhow::Error>::Ok(())
+        });
 
         seq += 1;
     }
 
+    while let Some(res) = rounds.join_next().await {
+        res??;
+    }
     Ok(())
 }
 
-async fn completions(
-    State(ctx): State<Arc<Context>>,
-    Json(req): Json<async_openai::types::CreateCompletionRequest>
+async fn dispatch(
+    ctx: Arc<Context>,
+    req: ApiRequest
 ) -> Response {
-    let (oneshot_tx, oneshot_rx) = tokio::sync::oneshot::channel();
+    if req.is_stream() {
+        let (event_tx, event_rx) = mpsc::channel(64);
+
+        if let Err(e) = ctx.task_sender.send((req, Responder::Stream(event_tx))).await {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap();
+        }
+
+        let events = futures_util::stream::unfold(event_rx, |mut rx| async move {
+            rx.recv().await.map(|event| (Ok::<_, Infallible>(event), rx))
+        });
+
+        return Sse::new(events).into_response();
+    }
+
+    let (oneshot_tx, oneshot_rx) = oneshot::channel();
 
     let fut = async {
-        ctx.task_sender.send((req, oneshot_tx)).await?;
+        ctx.task_sender.send((req, Responder::Unary(oneshot_tx))).await?;
         let resp= oneshot_rx.await?;
         Result::<_, anyhow::Error>::Ok(resp)
     };
 
     match fut.await {
         Ok(resp) => {
-            let ret = serde_json::to_vec(&resp).unwrap();
+            let ret = resp.to_json().unwrap();
             Response::new(Body::from(ret))
         }
         Err(e) => {
@@ -136,13 +428,36 @@ async fn completions(
     }
 }
 
+async fn completions(
+    State(ctx): State<Arc<Context>>,
+    Json(req): Json<CreateCompletionRequest>
+) -> Response {
+    dispatch(ctx, ApiRequest::Completion(req)).await
+}
+
+async fn chat_completions(
+    State(ctx): State<Arc<Context>>,
+    Json(req): Json<CreateChatCompletionRequest>
+) -> Response {
+    dispatch(ctx, ApiRequest::Chat(req)).await
+}
+
+async fn embeddings(
+    State(ctx): State<Arc<Context>>,
+    Json(req): Json<CreateEmbeddingRequest>
+) -> Response {
+    dispatch(ctx, ApiRequest::Embedding(req)).await
+}
+
 async fn daemon(
     bind_addr: SocketAddr,
     provider: Address,
     deopenchat_contact_address: Address,
     client_sk_str: &str,
-    chain_endpoint: Url
+    chain_endpoint: Url,
+    max_inflight: u32
 ) -> Result<()> {
+    ensure!(max_inflight > 0 && max_inflight <= SEQ_WINDOW, "max inflight must be within 1..={}", SEQ_WINDOW);
     let client = reqwest::Client::new();
 
     let client_sk = SigningKey::from(SecretKey::from_hex(client_sk_str)?);
@@ -158,27 +473,25 @@ async fn daemon(
         .await?
         ._0;
 
-    let provider_endpoint: Url = provider_info.endpoint.parse()?;
-    let query_seq = provider_endpoint
-        .join("/v1/completions/seq/")?
-        .join(&hex::encode(client_pk.to_bytes()))?;
+    // signatures only count for this provider on this deployment
+    let chain_id = alloy_provider.get_chain_id().await?;
+    let domain = Domain::new(chain_id, deopenchat_contact_address.into_array(), provider.into_array());
 
-    let seq_str = client.get(query_seq)
-        .send()
-        .await?
-        .text()
-        .await?;
+    let provider_endpoint: Url = provider_info.endpoint.parse()?;
+    let status = seq_status(&client, &provider_endpoint, &client_pk.to_bytes()).await?;
 
-    let seq = u32::from_str(&seq_str)?;
+    close_holes(&client, &provider_endpoint, &client_sk, &domain).await?;
 
-    let (task_tx, task_rx) = tokio::sync::mpsc::channel(64);
+    let (task_tx, task_rx) = mpsc::channel(64);
 
     let completions_fut = async {
         tokio::spawn(completions_handler(
             client,
             provider_info.endpoint.parse()?,
-            seq + 1,
+            status,
             client_sk,
+            domain,
+            max_inflight,
             task_rx,
         )).await?
     };
@@ -189,6 +502,8 @@ async fn daemon(
 
     let app = Router::new()
         .route("/v1/completions", get(completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/embeddings", post(embeddings))
         .with_state(Arc::new(ctx));
 
     let listener = tokio::net::TcpListener::bind(bind_addr).await?;
@@ -204,7 +519,8 @@ async fn fetch_tokens(
     deopenchat_contact_address: Address,
     wallet_sk: &str,
     provider: Address,
-    ktokens: u32,
+    input_ktokens: u32,
+    output_ktokens: u32,
     client_key: &str
 ) -> Result<()> {
     let signer = PrivateKeySigner::from_str(wallet_sk)?;
@@ -221,14 +537,22 @@ async fn fetch_tokens(
         .call()
         .await?;
 
-    let amount = provider_info._0.costPerKTokens * ktokens;
+    let costs = provider_info._0.costPerKUnits;
+    let input_cost = U256::from(costs[MeterUnit::InputTokens as usize]) * U256::from(input_ktokens);
+    let output_cost = U256::from(costs[MeterUnit::OutputTokens as usize]) * U256::from(output_ktokens);
+    let amount = input_cost + output_cost;
+
+    info!("input tokens: {} ktokens x {} = {}", input_ktokens, costs[MeterUnit::InputTokens as usize], input_cost);
+    info!("output tokens: {} ktokens x {} = {}", output_ktokens, costs[MeterUnit::OutputTokens as usize], output_cost);
+    info!("total amount: {}", amount);
 
     let tx = deopenchat.fethTokens(
         provider,
-        ktokens,
+        input_ktokens,
+        output_ktokens,
         FixedBytes::new(hex::decode_to_array::<_, 32>(client_key)?)
     )
-    .value(U256::from(amount))
+    .value(amount)
     .send()
     .await?
     .watch()
@@ -254,12 +578,16 @@ async fn print_all_providers(
 
     let mut table = Table::new();
 
-    table.add_row(row!["ADDRESS", "COST_PER_KTOKENS", "ENDPOINT", "MODEL"]);
+    table.add_row(row!["ADDRESS", "COST_PER_INPUT_KTOKENS", "COST_PER_OUTPUT_KTOKENS", "COST_PER_KIMAGES", "COST_PER_KAUDIO_SECONDS", "COST_PER_KDOCUMENTS", "ENDPOINT", "MODEL"]);
 
     for p in providers._0 {
         table.add_row(row![
             hex::encode(p.providerAddress),
-            p.costPerKTokens,
+            p.costPerKUnits[MeterUnit::InputTokens as usize],
+            p.costPerKUnits[MeterUnit::OutputTokens as usize],
+            p.costPerKUnits[MeterUnit::Images as usize],
+            p.costPerKUnits[MeterUnit::AudioSeconds as usize],
+            p.costPerKUnits[MeterUnit::Documents as usize],
             p.endpoint,
             p.model
         ]);
@@ -280,6 +608,9 @@ enum SubCommand {
 
         #[arg(short, long)]
         client_sk: String,
+
+        #[arg(long, default_value_t = SEQ_WINDOW)]
+        max_inflight: u32,
     },
     FetchTokens {
         #[arg(short, long)]
@@ -292,7 +623,10 @@ enum SubCommand {
         eth_wallet_sk: String,
 
         #[arg(short, long)]
-        ktokens: u32,
+        input_ktokens: u32,
+
+        #[arg(short, long)]
+        output_ktokens: u32,
     },
     PrintAllProviders
 }
@@ -348,28 +682,32 @@ fn exec(args: Args) -> Result<()> {
         SubCommand::Daemon {
             bind_addr,
             provider,
-            client_sk
+            client_sk,
+            max_inflight
         } => {
             rt.block_on(daemon(
                 bind_addr,
                 provider,
                 args.deopenchat_contact_address,
                 &client_sk,
-                args.chain_endpoint
+                args.chain_endpoint,
+                max_inflight
             ))
         }
         SubCommand::FetchTokens {
             provider,
             client_pk,
             eth_wallet_sk ,
-            ktokens
+            input_ktokens,
+            output_ktokens
         } => {
             rt.block_on(fetch_tokens(
                 args.chain_endpoint,
                 args.deopenchat_contact_address,
                 &eth_wallet_sk,
                 provider,
-                ktokens,
+                input_ktokens,
+                output_ktokens,
                 &client_pk
             ))
         }
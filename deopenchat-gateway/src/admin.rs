@@ -0,0 +1,140 @@
+use std::sync::Arc;
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use axum::body::Body;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use common::{PublicKey, METER_UNITS};
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use crate::metadata::PeerStatus;
+use crate::Context;
+
+#[derive(Serialize)]
+struct ClientInfo {
+    pk: String,
+    status: PeerStatus,
+    unclaimed_rounds: u32,
+    unclaimed_units: [u64; METER_UNITS],
+}
+
+fn json_response<V: Serialize>(res: anyhow::Result<V>) -> Response {
+    match res.and_then(|v| Ok(serde_json::to_vec(&v)?)) {
+        Ok(buf) => Response::new(Body::from(buf)),
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+async fn auth(State(token): State<Arc<String>>, req: Request, next: Next) -> Response {
+    let authorized = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())));
+
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(req).await
+}
+
+async fn clients<T, P>(State(ctx): State<Arc<Context<P>>>) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let clients = ctx.md_cache.statuses().await?
+            .into_iter()
+            .map(|(pk, status)| ClientInfo {
+                pk: hex::encode(pk),
+                unclaimed_rounds: status.confirmed_seq - status.commit_seq,
+                unclaimed_units: status.unclaimed,
+                status,
+            })
+            .collect::<Vec<_>>();
+
+        Ok::<_, anyhow::Error>(clients)
+    };
+
+    json_response(fut.await)
+}
+
+async fn rounds<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    Path(pk_str): Path<String>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let pk: PublicKey = hex::decode(pk_str)?.as_slice().try_into()?;
+
+        let status = ctx.md_cache.load_status(pk).await?
+            .ok_or_else(|| anyhow::anyhow!("unknown client"))?;
+
+        let mut rounds = Vec::new();
+
+        // claimed rounds are pruned, what is left is everything since the last claim
+        for seq in status.commit_seq + 1..=status.seq {
+            if let Ok(rd) = ctx.md_cache.load_round(pk, seq).await {
+                rounds.push(rd);
+            }
+        }
+
+        Ok::<_, anyhow::Error>(rounds)
+    };
+
+    json_response(fut.await)
+}
+
+async fn claims<T, P>(State(ctx): State<Arc<Context<P>>>) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    json_response(ctx.md_cache.claims())
+}
+
+async fn pending_claim<T, P>(State(ctx): State<Arc<Context<P>>>) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    json_response(ctx.md_cache.pending_claim())
+}
+
+async fn claim_now<T, P>(State(ctx): State<Arc<Context<P>>>) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    ctx.claim_now.notify_one();
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// Operator endpoints, every route requires `Authorization: Bearer <token>`.
+pub fn router<T, P>(ctx: Arc<Context<P>>, token: String) -> Router
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    Router::new()
+        .route("/clients", get(clients::<T, P>))
+        .route("/clients/:pk/rounds", get(rounds::<T, P>))
+        .route("/claims", get(claims::<T, P>))
+        .route("/claims/pending", get(pending_claim::<T, P>))
+        .route("/claims/trigger", post(claim_now::<T, P>))
+        .route_layer(middleware::from_fn_with_state(Arc::new(token), auth))
+        .with_state(ctx)
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use common::PublicKey;
+
+// abi encoding of one Deopenchat.Claim, nine 32 byte words
+const CLAIM_CALLDATA_SIZE: usize = 9 * 32;
+
+// selector, argument offsets, array and seal lengths plus a padded groth16 seal
+const BASE_CALLDATA_SIZE: usize = 4 + 4 * 32 + 288;
+
+/// Limits on what goes into one proof and its claim transaction.
+#[derive(Copy, Clone)]
+pub struct BatchBudget {
+    pub max_cycles: Option<u64>,
+    pub max_calldata: Option<usize>,
+    // rounds a client collects before they are proven ahead of a claim
+    pub min_segment_rounds: u32,
+}
+
+impl BatchBudget {
+    fn cycles_exceeded(&self, cycles: u64) -> bool {
+        self.max_cycles.is_some_and(|max| cycles > max)
+    }
+
+    fn calldata_exceeded(&self, clients: usize) -> bool {
+        self.max_calldata.is_some_and(|max| BASE_CALLDATA_SIZE + clients * CLAIM_CALLDATA_SIZE > max)
+    }
+}
+
+/// Takes the next batch off the front of `history` that fits `budget`, `true` if rounds are left behind.
+///
+/// Every client contributes a prefix of its rounds, so the rest stays claimable by a later batch.
+/// The first round always goes in, a batch that cannot hold one round would never make progress.
+pub fn next_batch<R, F>(
+    history: Vec<(PublicKey, Vec<R>)>,
+    budget: &BatchBudget,
+    base_cycles: u64,
+    mut round_cycles: F
+) -> Result<(Vec<(PublicKey, Vec<R>)>, bool)>
+    where
+        F: FnMut(&PublicKey, &R) -> Result<u64>
+{
+    let mut batch: Vec<(PublicKey, Vec<R>)> = Vec::new();
+    let mut cycles = base_cycles;
+
+    for (pk, rounds) in history {
+        if !batch.is_empty() && budget.calldata_exceeded(batch.len() + 1) {
+            return Ok((batch, true));
+        }
+
+        let total = rounds.len();
+        let mut taken = Vec::new();
+
+        for round in rounds {
+            let c = round_cycles(&pk, &round)?;
+
+            if (!batch.is_empty() || !taken.is_empty()) && budget.cycles_exceeded(cycles + c) {
+                break;
+            }
+
+            cycles += c;
+            taken.push(round);
+        }
+
+        if taken.is_empty() {
+            return Ok((batch, true));
+        }
+
+        let partial = taken.len() < total;
+        batch.push((pk, taken));
+
+        if partial {
+            return Ok((batch, true));
+        }
+    }
+
+    Ok((batch, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_cycles_and_calldata() {
+        let history = vec![
+            ([1u8; 32], vec![10u64, 10, 10]),
+            ([2u8; 32], vec![10u64, 10]),
+        ];
+
+        let budget = BatchBudget { max_cycles: Some(45), max_calldata: None, min_segment_rounds: 1 };
+        let (batch, more) = next_batch(history.clone(), &budget, 5, |_, c| Ok(*c)).unwrap();
+
+        assert!(more);
+        assert_eq!(batch, vec![([1u8; 32], vec![10, 10, 10]), ([2u8; 32], vec![10])]);
+
+        let budget = BatchBudget { max_cycles: None, max_calldata: Some(BASE_CALLDATA_SIZE + CLAIM_CALLDATA_SIZE), min_segment_rounds: 1 };
+        let (batch, more) = next_batch(history.clone(), &budget, 5, |_, c| Ok(*c)).unwrap();
+
+        assert!(more);
+        assert_eq!(batch, vec![([1u8; 32], vec![10, 10, 10])]);
+
+        let budget = BatchBudget { max_cycles: Some(1), max_calldata: None, min_segment_rounds: 1 };
+        let (batch, more) = next_batch(history, &budget, 5, |_, c| Ok(*c)).unwrap();
+
+        assert!(more);
+        assert_eq!(batch, vec![([1u8; 32], vec![10])]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use async_openai::types::{CreateCompletionRequest, CreateCompletionResponse};
+use common::api::{ApiRequest, ApiResponse};
+use common::{CompletionsReq, CompletionsResp, Confirm, ConfirmMsg, ConfirmReq, MeterUnit, PublicKey, Request, RequestMsg, METER_UNITS};
+use serde::Deserialize;
+use crate::metadata::{InflightRound, PeerStatus, RoundData, RoundState};
+use crate::store::Table;
+
+// a cacache gateway served one round at a time, `state` is the one of `seq`
+#[derive(Deserialize)]
+struct LegacyStatus {
+    seq: u32,
+    commit_seq: u32,
+    state: RoundState,
+}
+
+#[derive(Deserialize)]
+struct LegacyRequestMsg {
+    seq: u32,
+}
+
+#[derive(Deserialize)]
+struct LegacyRequest {
+    msg: LegacyRequestMsg,
+    signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct LegacyCompletionsReq {
+    pk: PublicKey,
+    raw_req: CreateCompletionRequest,
+    request: LegacyRequest,
+}
+
+#[derive(Deserialize)]
+struct LegacyConfirmMsg {
+    seq: u32,
+    input_tokens: u32,
+    resp_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct LegacyConfirm {
+    msg: LegacyConfirmMsg,
+    signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct LegacyConfirmReq {
+    pk: PublicKey,
+    confirm: LegacyConfirm,
+}
+
+// written once the backend answered, a round still requested has none
+#[derive(Deserialize)]
+struct LegacyRound {
+    seq: u32,
+    req: LegacyCompletionsReq,
+    resp: CompletionsResp<CreateCompletionResponse>,
+    confirm_msg: Option<LegacyConfirmReq>,
+}
+
+fn status(old: LegacyStatus) -> PeerStatus {
+    let (seq, confirmed_seq, inflight) = match old.state {
+        RoundState::Completed => (old.seq, old.seq, BTreeMap::new()),
+        // never answered, the client signs the seq again
+        RoundState::Requested => (old.seq.saturating_sub(1), old.seq.saturating_sub(1), BTreeMap::new()),
+        // past its deadline, voided unless the client confirms it first
+        RoundState::WaitingConfirm => {
+            let round = InflightRound {
+                state: RoundState::WaitingConfirm,
+                deadline: 0,
+                reserved: [0; METER_UNITS],
+                requested_at: 0,
+            };
+
+            (old.seq, old.seq - 1, BTreeMap::from([(old.seq, round)]))
+        }
+    };
+
+    // the signatures carry no domain, nothing of it can be claimed anymore so nothing is billed
+    PeerStatus {
+        seq,
+        commit_seq: old.commit_seq,
+        confirmed_seq,
+        proven_seq: old.commit_seq,
+        inflight,
+        unclaimed: [0; METER_UNITS],
+        unclaimed_since: None,
+        unprovable_seq: None,
+    }
+}
+
+fn round(old: LegacyRound) -> Result<RoundData> {
+    let raw_req = ApiRequest::Completion(old.req.raw_req);
+    let raw_response = ApiResponse::Completion(old.resp.raw_response);
+
+    // the client signed neither digest, they are filled in so the round reads like any other
+    let req_hash = raw_req.content()?.digest();
+    let resp_hash = raw_response.digest()?;
+
+    let confirm_msg = old.confirm_msg.map(|c| {
+        let mut usage = [0; METER_UNITS];
+        usage[MeterUnit::InputTokens as usize] = c.confirm.msg.input_tokens;
+        usage[MeterUnit::OutputTokens as usize] = c.confirm.msg.resp_tokens;
+
+        ConfirmReq {
+            pk: c.pk,
+            confirm: Confirm {
+                msg: ConfirmMsg { seq: c.confirm.msg.seq, resp_hash, usage },
+                signature: c.confirm.signature,
+            },
+        }
+    });
+
+    Ok(RoundData {
+        // rounds of version 0 are never proven, see `MetadataCache::load_peers`
+        version: 0,
+        seq: old.seq,
+        requested_at: 0,
+        req: CompletionsReq {
+            pk: old.req.pk,
+            raw_req,
+            request: Request {
+                msg: RequestMsg { seq: old.req.request.msg.seq, req_hash },
+                signature: old.req.request.signature,
+            },
+        },
+        resp: Some(CompletionsResp { raw_response }),
+        confirm_msg,
+    })
+}
+
+/// Entry of a cacache gateway's `table` in the format `MetadataCache` reads.
+pub fn convert(table: Table, value: &[u8]) -> Result<Vec<u8>> {
+    match table {
+        Table::Status => Ok(serde_json::to_vec(&status(serde_json::from_slice(value)?))?),
+        Table::History => Ok(serde_json::to_vec(&round(serde_json::from_slice(value)?)?)?),
+        _ => Ok(value.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::metadata::MetadataCache;
+    use crate::store::{Batch, MemoryStore, MetadataStore};
+    use super::*;
+
+    const PK: PublicKey = [7; 32];
+    const SIGNATURE: [u8; 64] = [0; 64];
+
+    fn legacy_round(seq: u32, confirmed: bool) -> Value {
+        let confirm_msg = confirmed.then(|| json!({
+            "pk": PK,
+            "confirm": {
+                "msg": { "seq": seq, "input_tokens": 3, "resp_tokens": 5 },
+                "signature": SIGNATURE.to_vec()
+            }
+        }));
+
+        json!({
+            "seq": seq,
+            "req": {
+                "pk": PK,
+                "raw_req": { "model": "m", "prompt": "hi" },
+                "request": { "msg": { "seq": seq }, "signature": SIGNATURE.to_vec() }
+            },
+            "resp": {
+                "raw_response": {
+                    "id": "cmpl",
+                    "choices": [{ "text": "hello", "index": 0, "logprobs": null, "finish_reason": "stop" }],
+                    "created": 0,
+                    "model": "m",
+                    "object": "text_completion"
+                }
+            },
+            "confirm_msg": confirm_msg
+        })
+    }
+
+    #[tokio::test]
+    async fn imports_cacache_records() {
+        let key_str = hex::encode(PK);
+
+        let legacy = [
+            (Table::Status, key_str.clone(), json!({ "seq": 3, "commit_seq": 1, "state": "WaitingConfirm" })),
+            (Table::History, format!("{}-2", key_str), legacy_round(2, true)),
+            (Table::History, format!("{}-3", key_str), legacy_round(3, false)),
+        ];
+
+        let store = MemoryStore::default();
+        let mut batch = Batch::default();
+
+        for (table, key, value) in legacy {
+            let converted = convert(table, &serde_json::to_vec(&value).unwrap()).unwrap();
+            batch.put(table, key, &serde_json::from_slice::<Value>(&converted).unwrap()).unwrap();
+        }
+        store.write(batch).unwrap();
+
+        let cache = MetadataCache::new(Box::new(store), Duration::from_secs(60), Duration::from_secs(60));
+        cache.load_peers().await.unwrap();
+
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert_eq!((status.seq, status.commit_seq, status.confirmed_seq), (3, 1, 2));
+        assert!(status.inflight[&3].state == RoundState::WaitingConfirm);
+        assert_eq!(status.unprovable_seq, Some(2));
+
+        let rd = cache.load_round(PK, 2).await.unwrap();
+        assert!(matches!(rd.req.raw_req, ApiRequest::Completion(_)));
+        assert_eq!(rd.confirm_msg().usage, [3, 5, 0, 0, 0]);
+
+        // the open round is voided like any other past its deadline
+        cache.void(PK, 3).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().confirmed_seq, 3);
+    }
+}
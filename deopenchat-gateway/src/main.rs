@@ -1,6 +1,13 @@
-use crate::metadata::{MetadataCache, PeerStatus, RoundState};
+use crate::metadata::{MetadataCache, PendingClaim, SegmentReceipt};
+use crate::batch::{next_batch, BatchBudget};
+use crate::metrics::{kind_label, result_label, Metrics};
+use crate::prover::ProverTarget;
+use crate::schedule::{Backlog, ClaimPolicy, ClaimWindow};
+use crate::store::RedbStore;
+use deopenchat_prover::remote::RemoteProver;
+use deopenchat_prover::ProverKind;
 use alloy::network::EthereumWallet;
-use alloy::primitives::{Address, Bytes, FixedBytes};
+use alloy::primitives::{Address, Bytes, FixedBytes, U256};
 use alloy::providers::{Provider, ProviderBuilder, WalletProvider};
 use alloy::signers::local::PrivateKeySigner;
 use alloy::sol;
@@ -8,29 +15,46 @@ use alloy::transports::http::reqwest::Url;
 use alloy::transports::Transport;
 use anyhow::{anyhow, ensure, Result};
 use async_openai::config::OpenAIConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{ChatCompletionStreamOptions, CreateChatCompletionRequest, CreateCompletionRequest, CreateEmbeddingRequest};
 use axum::body::Body;
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::response::Response;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use clap::{Parser, Subcommand};
-use common::{CompletionsReq, CompletionsResp, ConfirmReq, Input, PublicKey, Round, CLAIM_SIZE};
+use common::circuit::CircuitError;
+use common::journal::ClaimJournal;
+use common::api::{ApiRequest, ApiResponse, ChatAggregator, CompletionAggregator, StreamAggregator};
+use common::{AbandonReq, CompletionsReq, CompletionsResp, ConfirmMsg, ConfirmReq, Domain, Input, MeterUnit, PublicKey, Round, RequestKind, SeqStatus, METER_UNITS};
 use ed25519_dalek::{ed25519, Verifier, VerifyingKey};
-use futures_util::TryFutureExt;
-use log::{info, LevelFilter};
+use futures_util::{Stream, StreamExt, TryFutureExt};
+use log::{error, info, warn, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::future::IntoFuture;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::ExitCode;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
 
+mod admin;
+mod batch;
+mod legacy;
 mod metadata;
+mod metrics;
+mod prover;
+mod schedule;
+mod store;
 
 sol!{
     #[sol(rpc)]
@@ -69,13 +93,135 @@ struct Context<P> {
     alloy_provider: P,
     provider_address: Address,
     deopenchat_contact_address: Address,
+    // what clients sign for, see `Domain`
+    domain: Domain,
     backend_client: async_openai::Client<OpenAIConfig>,
-    accumulated_tokens: AtomicU64
+    // last prices read from chain, see `costs_per_kunits`
+    costs_per_kunits: std::sync::RwLock<[u32; METER_UNITS]>,
+    metrics: Metrics,
+    prover: ProverTarget,
+    // claims may carry other than groth16 seals, see `--mock-verifier`
+    mock_verifier: bool,
+    // wakes the commit loop to claim regardless of the high water level
+    claim_now: Notify,
 }
 
-async fn completions<T, P>(
-    State(ctx): State<Arc<Context<P>>>,
-    Json(req): Json<CompletionsReq<async_openai::types::CreateCompletionRequest>>
+fn units_cost(units: &[u64; METER_UNITS], costs_per_kunits: &[u32; METER_UNITS]) -> U256 {
+    let cost: u128 = units.iter()
+        .zip(costs_per_kunits)
+        .map(|(&amount, &cost)| amount as u128 * cost as u128)
+        .sum();
+
+    U256::from(cost / 1000)
+}
+
+/// Prices of the provider as registered now, the operator may lower them at any time.
+async fn costs_per_kunits<T, P>(ctx: &Context<P>) -> Result<[u32; METER_UNITS]>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let costs = Deopenchat::new(ctx.deopenchat_contact_address, &ctx.alloy_provider)
+        .getProvider(ctx.provider_address)
+        .call()
+        .await?
+        ._0
+        .costPerKUnits;
+
+    *ctx.costs_per_kunits.write().unwrap() = costs;
+    Ok(costs)
+}
+
+/// Units a round may bill at most, the prompt length bounds its input tokens.
+fn reserved_units(prompt_len: usize, max_tokens: Option<u32>) -> [u64; METER_UNITS] {
+    let mut units = [0u64; METER_UNITS];
+    units[MeterUnit::InputTokens as usize] = prompt_len as u64;
+    units[MeterUnit::OutputTokens as usize] = max_tokens.unwrap_or(0) as u64;
+    units
+}
+
+/// Output tokens the client can still pay for after the prompt, `None` if output is free.
+fn affordable_output_tokens(
+    spendable: U256,
+    prompt_len: usize,
+    costs_per_kunits: &[u32; METER_UNITS]
+) -> Result<Option<u32>> {
+    // a token spans at least one byte, so the prompt length bounds the input tokens from above
+    let mut units = [0u64; METER_UNITS];
+    units[MeterUnit::InputTokens as usize] = prompt_len as u64;
+
+    let input_cost = units_cost(&units, costs_per_kunits);
+    ensure!(spendable >= input_cost, "insufficient balance for the prompt");
+
+    let output_cost = costs_per_kunits[MeterUnit::OutputTokens as usize];
+
+    if output_cost == 0 {
+        return Ok(None);
+    }
+
+    let tokens = (spendable - input_cost) * U256::from(1000) / U256::from(output_cost);
+    Ok(Some(tokens.saturating_to::<u32>()))
+}
+
+fn api_stream<T, P, A>(
+    ctx: Arc<Context<P>>,
+    req: CompletionsReq<ApiRequest>,
+    mut stream: Pin<Box<dyn Stream<Item = Result<A::Chunk, OpenAIError>> + Send>>,
+    mut aggregator: A
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static,
+        A: StreamAggregator + Send + 'static,
+        A::Chunk: Send
+{
+    let (tx, rx) = mpsc::channel::<Event>(64);
+
+    tokio::spawn(async move {
+        let fut = async {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                // keep draining the backend even if the client went away, the round is still billed
+                let _ = tx.send(Event::default().json_data(&chunk)?).await;
+                aggregator.push(chunk);
+            }
+
+            let cr = CompletionsResp {
+                raw_response: aggregator.finish().ok_or_else(|| anyhow!("empty stream"))?,
+            };
+
+            // never guess usage, a round the backend did not meter is voided
+            ensure!(cr.raw_response.usage().is_some(), "backend reported no usage");
+
+            ctx.md_cache.resp(&req, &cr).await?;
+            Result::<_, anyhow::Error>::Ok(())
+        };
+
+        let event = match fut.await {
+            Ok(_) => Event::default().data("[DONE]"),
+            Err(e) => {
+                error!("stream error: {:?}", e);
+
+                if let Err(e) = ctx.md_cache.void(req.pk, req.request.msg.seq).await {
+                    error!("void round error: {:?}", e);
+                }
+                Event::default().event("error").data(e.to_string())
+            }
+        };
+
+        let _ = tx.send(event).await;
+    });
+
+    let events = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+
+    Sse::new(events).into_response()
+}
+
+async fn metered_round<T, P>(
+    ctx: Arc<Context<P>>,
+    req: CompletionsReq<ApiRequest>
 ) -> Response
     where
         T: Send + Sync + Transport + Clone,
@@ -84,33 +230,50 @@ async fn completions<T, P>(
     let fut = async {
         let vk = VerifyingKey::from_bytes(&req.pk)?;
         let signature= ed25519::Signature::from_slice(&req.request.signature)?;
-        vk.verify(&<[u8; 4]>::from(req.request.msg), &signature)?;
+        vk.verify(&ctx.domain.request_payload(req.request.msg), &signature)
+            .inspect_err(|_| ctx.metrics.signature_failures.with_label_values(&["request"]).inc())?;
 
         let deopenchat = Deopenchat::new(ctx.deopenchat_contact_address, &ctx.alloy_provider);
         let builder = deopenchat.viewStatus(ctx.provider_address, FixedBytes::new(req.pk));
         let record= builder.call().await?._0;
 
-        anyhow::ensure!(record.remainingTokens > 0);
-        ctx.md_cache.req(&req).await?;
+        let content = req.raw_req.content()?;
+        ensure!(req.request.msg.req_hash == content.digest(), "request hash mismatch");
 
-        let resp = ctx.backend_client
-            .completions()
-            .create(req.raw_req.clone())
-            .await?;
+        let costs_per_kunits = costs_per_kunits::<T, P>(&ctx).await?;
 
-        let cr = CompletionsResp {
-            raw_response: resp,
-        };
+        // only the forwarded copy is clamped, the signed request stays as the client sent it
+        let mut backend_req = req.raw_req.clone();
 
-        ctx.md_cache.resp(&req, &cr).await?;
-        Ok(cr)
-    };
+        // open rounds hold their worst case until confirmed, so the window can't overspend the balance
+        ctx.md_cache.req(&req, |held| {
+            let spendable = record.balance.saturating_sub(units_cost(held, &costs_per_kunits));
+            let affordable = affordable_output_tokens(spendable, content.input.len(), &costs_per_kunits)?;
 
-    match fut.await {
-        Ok(resp) => {
-            let ret = serde_json::to_vec(&resp).unwrap();
-            Response::new(Body::from(ret))
+            if let (Some(limit), false) = (affordable, content.kind == RequestKind::Embedding) {
+                ensure!(limit > 0, "insufficient balance for the completion");
+                backend_req.clamp_max_tokens(limit);
+            }
+            Ok(reserved_units(content.input.len(), backend_req.max_tokens()))
+        }).await?;
+
+        match forward::<T, P>(ctx.clone(), req.clone(), backend_req).await {
+            Ok(resp) => Ok(resp),
+            Err(e) => {
+                // the round failed before any response, close it so the window moves on
+                if let Err(e) = ctx.md_cache.void(req.pk, req.request.msg.seq).await {
+                    error!("void round error: {:?}", e);
+                }
+                Err(e)
+            }
         }
+    };
+
+    let res = fut.await;
+    ctx.metrics.requests.with_label_values(&[kind_label(&req.raw_req), result_label(&res)]).inc();
+
+    match res {
+        Ok(resp) => resp,
         Err(e) => {
             Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -120,6 +283,114 @@ async fn completions<T, P>(
     }
 }
 
+async fn forward<T, P>(
+    ctx: Arc<Context<P>>,
+    req: CompletionsReq<ApiRequest>,
+    backend_req: ApiRequest
+) -> Result<Response>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let include_usage = Some(ChatCompletionStreamOptions { include_usage: true });
+
+    // observed on drop too, streams stop the clock once the backend starts sending
+    let timer = ctx.metrics.backend_latency.with_label_values(&[kind_label(&backend_req)]).start_timer();
+
+    let resp = match &backend_req {
+        ApiRequest::Completion(raw) if raw.stream == Some(true) => {
+            let mut raw = raw.clone();
+            raw.stream_options = include_usage;
+
+            let stream = ctx.backend_client
+                .completions()
+                .create_stream(raw)
+                .await?;
+
+            return Ok(api_stream::<T, P, _>(ctx.clone(), req.clone(), stream, CompletionAggregator::default()));
+        }
+        ApiRequest::Chat(raw) if raw.stream == Some(true) => {
+            let mut raw = raw.clone();
+            raw.stream_options = include_usage;
+
+            let stream = ctx.backend_client
+                .chat()
+                .create_stream(raw)
+                .await?;
+
+            return Ok(api_stream::<T, P, _>(ctx.clone(), req.clone(), stream, ChatAggregator::default()));
+        }
+        ApiRequest::Completion(raw) => {
+            let resp = ctx.backend_client
+                .completions()
+                .create(raw.clone())
+                .await?;
+
+            ApiResponse::Completion(resp)
+        }
+        ApiRequest::Chat(raw) => {
+            let resp = ctx.backend_client
+                .chat()
+                .create(raw.clone())
+                .await?;
+
+            ApiResponse::Chat(resp)
+        }
+        ApiRequest::Embedding(raw) => {
+            let resp = ctx.backend_client
+                .embeddings()
+                .create(raw.clone())
+                .await?;
+
+            ApiResponse::Embedding(resp)
+        }
+    };
+
+    timer.observe_duration();
+
+    let cr = CompletionsResp {
+        raw_response: resp,
+    };
+
+    ctx.md_cache.resp(&req, &cr).await?;
+
+    let ret = serde_json::to_vec(&cr)?;
+    Ok(Response::new(Body::from(ret)))
+}
+
+async fn completions<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    Json(req): Json<CompletionsReq<CreateCompletionRequest>>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    metered_round::<T, P>(ctx, req.map(ApiRequest::Completion)).await
+}
+
+async fn chat_completions<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    Json(req): Json<CompletionsReq<CreateChatCompletionRequest>>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    metered_round::<T, P>(ctx, req.map(ApiRequest::Chat)).await
+}
+
+async fn embeddings<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    Json(req): Json<CompletionsReq<CreateEmbeddingRequest>>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    metered_round::<T, P>(ctx, req.map(ApiRequest::Embedding)).await
+}
+
 async fn completions_confirm<T, P>(
     State(ctx): State<Arc<Context<P>>>,
     Json(req): Json<ConfirmReq>
@@ -131,19 +402,66 @@ async fn completions_confirm<T, P>(
     let fut = async {
         let vk = VerifyingKey::from_bytes(&req.pk)?;
         let signature= ed25519::Signature::from_slice(&req.confirm.signature)?;
-        vk.verify(&<[u8; 12]>::from(req.confirm.msg), &signature)?;
+        vk.verify(&ctx.domain.confirm_payload(req.confirm.msg), &signature)
+            .inspect_err(|_| ctx.metrics.signature_failures.with_label_values(&["confirm"]).inc())?;
 
         let rd = ctx.md_cache.load_round(req.pk, req.confirm.msg.seq).await?;
-        let usage = rd.resp.raw_response.usage.ok_or_else(|| anyhow!("missing usage"))?;
+        let resp = rd.resp.ok_or_else(|| anyhow!("round {} has no response", rd.seq))?;
+        ensure!(req.confirm.msg.resp_hash == resp.raw_response.digest()?, "response hash mismatch");
+
+        let usage = resp.raw_response.usage().ok_or_else(|| anyhow!("missing usage"))?;
 
-        ensure!(req.confirm.msg.input_tokens >= usage.prompt_tokens);
-        ensure!(req.confirm.msg.resp_tokens >= usage.completion_tokens);
+        ensure!(req.confirm.msg.usage[MeterUnit::InputTokens as usize] >= usage.prompt_tokens);
+        ensure!(req.confirm.msg.usage[MeterUnit::OutputTokens as usize] >= usage.completion_tokens);
 
         ctx.md_cache.confirm(&req).await?;
-        ctx.accumulated_tokens.fetch_add(req.confirm.msg.input_tokens as u64 + req.confirm.msg.resp_tokens as u64, Ordering::Relaxed);
         Ok(())
     };
 
+    let res = fut.await;
+    ctx.metrics.confirms.with_label_values(&[result_label(&res)]).inc();
+
+    match res {
+        Ok(resp) => {
+            let ret = serde_json::to_vec(&resp).unwrap();
+            Response::new(Body::from(ret))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+async fn completions_abandon<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    Json(req): Json<AbandonReq<ApiRequest>>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let vk = VerifyingKey::from_bytes(&req.req.pk)?;
+
+        let signature= ed25519::Signature::from_slice(&req.req.request.signature)?;
+        vk.verify(&ctx.domain.request_payload(req.req.request.msg), &signature)
+            .inspect_err(|_| ctx.metrics.signature_failures.with_label_values(&["request"]).inc())?;
+        ensure!(req.req.request.msg.req_hash == req.req.raw_req.content()?.digest(), "request hash mismatch");
+
+        let seq = req.req.request.msg.seq;
+        ensure!(req.confirm.msg == ConfirmMsg::void(seq), "abandon must confirm zero usage");
+
+        let signature= ed25519::Signature::from_slice(&req.confirm.signature)?;
+        vk.verify(&ctx.domain.confirm_payload(req.confirm.msg), &signature)
+            .inspect_err(|_| ctx.metrics.signature_failures.with_label_values(&["confirm"]).inc())?;
+
+        ctx.md_cache.abandon(&req).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+
     match fut.await {
         Ok(resp) => {
             let ret = serde_json::to_vec(&resp).unwrap();
@@ -158,6 +476,29 @@ async fn completions_confirm<T, P>(
     }
 }
 
+async fn metrics<T, P>(
+    State(ctx): State<Arc<Context<P>>>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let statuses = ctx.md_cache.statuses().await?;
+        ctx.metrics.render(&statuses)
+    };
+
+    match fut.await {
+        Ok(buf) => Response::new(Body::from(buf)),
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
 async fn current_seq<T, P>(
     State(ctx): State<Arc<Context<P>>>,
     axum::extract::Path(pk_str): axum::extract::Path<String>
@@ -178,23 +519,77 @@ async fn current_seq<T, P>(
                     .call()
                     .await?._0;
 
-                let status = PeerStatus {
-                    seq: status.seq,
-                    commit_seq: status.seq,
-                    state: RoundState::Completed
-                };
-
-                ctx.md_cache.update_from_chain(pk, status.clone()).await?;
-                status
+                ctx.md_cache.update_from_chain(pk, status.seq).await?;
+                ctx.md_cache.load_status(pk).await?.ok_or_else(|| anyhow!("no status for client"))?
             }
         };
 
-        Ok::<_, anyhow::Error>(status.seq)
+        Ok::<_, anyhow::Error>(SeqStatus { seq: status.seq, confirmed_seq: status.confirmed_seq })
     };
 
     match fut.await {
-        Ok(seq) => {
-            Response::new(Body::from(seq.to_string()))
+        Ok(status) => {
+            let ret = serde_json::to_vec(&status).unwrap();
+            Response::new(Body::from(ret))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+async fn holes<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    axum::extract::Path(pk_str): axum::extract::Path<String>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let pk: PublicKey = hex::decode(pk_str)?.as_slice().try_into()?;
+        let holes = ctx.md_cache.load_status(pk).await?
+            .map(|s| s.holes())
+            .unwrap_or_default();
+
+        Ok::<_, anyhow::Error>(holes)
+    };
+
+    match fut.await {
+        Ok(holes) => {
+            let ret = serde_json::to_vec(&holes).unwrap();
+            Response::new(Body::from(ret))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+async fn transcript_proof<T, P>(
+    State(ctx): State<Arc<Context<P>>>,
+    axum::extract::Path((pk_str, seq)): axum::extract::Path<(String, u32)>
+) -> Response
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let fut = async {
+        let pk: PublicKey = hex::decode(pk_str)?.as_slice().try_into()?;
+        let proof = ctx.md_cache.transcript_proof(pk, seq).await?;
+        Ok::<_, anyhow::Error>(proof)
+    };
+
+    match fut.await {
+        Ok(proof) => {
+            let ret = serde_json::to_vec(&proof).unwrap();
+            Response::new(Body::from(ret))
         }
         Err(e) => {
             Response::builder()
@@ -207,7 +602,8 @@ async fn current_seq<T, P>(
 
 async fn commit_handler<T, P> (
     ctx: Arc<Context<P>>,
-    commit_high_water_level: u64
+    policy: ClaimPolicy,
+    budget: BatchBudget
 ) -> Result<()>
     where
         T: Send + Sync + Transport + Clone,
@@ -225,82 +621,427 @@ async fn commit_handler<T, P> (
 
     ensure!(prover_image_id.as_bytes() == contact_image_id, "contact image id mismatch, expected: {}, got: {}", prover_image_id, hex::encode(contact_image_id));
 
+    let segment_image_id = deopenchat_prover::segment_image_id();
+    let contact_segment_image_id = deopenchat.getSegmentImageId()
+        .call()
+        .await?
+        ._0
+        .0;
+
+    ensure!(segment_image_id.as_bytes() == contact_segment_image_id, "contact segment image id mismatch, expected: {}, got: {}", segment_image_id, hex::encode(contact_segment_image_id));
+
+    let mut failures = 0u32;
+
     loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        let forced = tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(3)) => false,
+            _ = ctx.claim_now.notified() => true,
+        };
 
-        if ctx.accumulated_tokens.load(Ordering::Relaxed) < commit_high_water_level {
-            continue;
+        // the ledger keeps the claim across failures, the next attempt resumes from it
+        match claim_step::<T, P>(&ctx, &policy, &budget, forced).await {
+            Ok(_) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                let backoff = (3u64 << failures.min(7)).min(300);
+                error!("claim error, attempt: {}, retry in {}s: {:?}", failures, backoff, e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(backoff)).await;
+            }
         }
+    }
+}
 
-        let mapping = ctx.md_cache.load_all_history().await?;
-        let rounds = mapping.into_iter()
-            .map(|(k, rounds)| {
-                let rounds= rounds.into_iter()
-                    .filter(|r| r.confirm_msg.is_some())
-                    .map(|round| {
-                        Round {
-                            request: round.req.request,
-                            confirm: round.confirm_msg.unwrap().confirm
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                (k, rounds)
-            })
-            .collect::<HashMap<_, _>>();
-
-        let input = Input {
-            rounds
-        };
+async fn claim_step<T, P>(
+    ctx: &Arc<Context<P>>,
+    policy: &ClaimPolicy,
+    budget: &BatchBudget,
+    forced: bool
+) -> Result<()>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let mut more = false;
+
+    let pending = match ctx.md_cache.pending_claim()? {
+        Some(pending) => pending,
+        None => {
+            // an operator trigger bypasses the schedule
+            if !forced && !claim_due::<T, P>(ctx, policy).await? {
+                // rounds are proven ahead between claims, so a claim only has to aggregate them
+                prove_segments(ctx, policy.max_rounds, budget, budget.min_segment_rounds).await?;
+                return Ok(());
+            }
+
+            while prove_segments(ctx, policy.max_rounds, budget, 1).await? {}
+
+            match prove_claim(ctx, policy.max_rounds, budget).await? {
+                Some((pending, left)) => {
+                    more = left;
+                    pending
+                }
+                None => return Ok(()),
+            }
+        }
+    };
+
+    submit_claim::<T, P>(ctx, pending).await?;
+
+    // whatever did not fit the budget goes out in the next batch right away
+    if more {
+        ctx.claim_now.notify_one();
+    }
+    Ok(())
+}
+
+async fn claim_due<T, P>(ctx: &Context<P>, policy: &ClaimPolicy) -> Result<bool>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let clients = ctx.md_cache.statuses().await?
+        .into_iter()
+        .filter(|(_, status)| status.confirmed_seq > status.commit_seq)
+        .map(|(pk, status)| (pk, status.unclaimed))
+        .collect::<Vec<_>>();
+
+    // priced at the last known costs, the chain is only asked once a claim is due
+    let known_costs = *ctx.costs_per_kunits.read().unwrap();
+
+    let backlog = Backlog {
+        clients: clients.iter()
+            .map(|(pk, units)| (*pk, units_cost(units, &known_costs).saturating_to::<u128>()))
+            .collect(),
+        oldest: ctx.md_cache.oldest_unclaimed().await?,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    if backlog.clients.is_empty() || !policy.due(&backlog, now) {
+        return Ok(false);
+    }
+
+    let min_profit_ratio = match policy.min_profit_ratio {
+        None => return Ok(true),
+        Some(ratio) => ratio,
+    };
+
+    let costs_per_kunits = costs_per_kunits::<T, P>(ctx).await?;
+
+    // checked before proving, a claim that does not pay for its gas is not worth the proving time
+    let payout = clients.iter()
+        .map(|(_, units)| units_cost(units, &costs_per_kunits))
+        .fold(U256::ZERO, |acc, cost| acc + cost);
+
+    let gas_price = ctx.alloy_provider.get_gas_price().await?;
+    let gas_cost = U256::from(policy.estimated_gas(backlog.clients.len())) * U256::from(gas_price);
+
+    // ratio in thousandths to stay in integer arithmetic
+    let profitable = payout * U256::from(1000) >= gas_cost * U256::from((min_profit_ratio * 1000.0) as u64);
+
+    if !profitable {
+        info!("claim postponed, payout: {} wei, estimated gas cost: {} wei", payout, gas_cost);
+    }
+    Ok(profitable)
+}
 
-        let prove_info = tokio::task::spawn_blocking(move || {
-            deopenchat_prover::prove(input)
-        }).await??;
+/// Cycles of one round on its own, the guest overhead of an empty input taken off.
+///
+/// Each estimate also carries the claim encoding of a single client, so a batch errs on the large side.
+fn round_cycles(domain: Domain, pk: &PublicKey, round: &Round, base_cycles: u64) -> Result<u64> {
+    let input = Input {
+        domain,
+        rounds: BTreeMap::from([(*pk, vec![round.clone()])])
+    };
 
-        let seal = risc0_ethereum_contracts::encode_seal(&prove_info.receipt)?;
-        let journal = prove_info.receipt.journal.bytes;
+    let cycles = deopenchat_prover::execute(&input)?;
+    Ok(cycles.saturating_sub(base_cycles))
+}
 
-        let mut buff = journal.as_slice();
-        let mut claims = Vec::new();
+// next segment of one client's rounds within the cycle budget, `true` if rounds are left
+async fn next_segment(domain: Domain, pk: PublicKey, rounds: Vec<Round>, budget: &BatchBudget) -> Result<(Vec<Round>, bool)> {
+    let history = vec![(pk, rounds)];
 
-        while buff.len() > 0 {
-            let (claim_buf, r) = journal.split_at(CLAIM_SIZE);
-            buff = r;
+    let (mut batch, more) = if budget.max_cycles.is_some() {
+        let budget = *budget;
 
-            let claim = Deopenchat::Claim {
-                clientPk: FixedBytes::new((&claim_buf[..32]).try_into().unwrap()),
-                seq: u32::from_be_bytes((&claim_buf[32..36]).try_into().unwrap()),
-                rounds: u32::from_be_bytes((&claim_buf[36..40]).try_into().unwrap()),
-                numberTokensConsumed: u64::from_be_bytes((&claim_buf[40..48]).try_into().unwrap()),
+        tokio::task::spawn_blocking(move || {
+            let empty = Input {
+                domain,
+                rounds: BTreeMap::new()
             };
-            claims.push(claim);
-        }
+            let base_cycles = deopenchat_prover::execute(&empty)?;
 
-        let commit_claims = claims.iter()
-            .map(|c| {
-                common::Claim {
-                    pk: c.clientPk.0,
-                    start_seq: c.seq,
-                    rounds: c.rounds,
-                    tokens_consumed: c.numberTokensConsumed,
+            next_batch(history, &budget, base_cycles, |pk, round| round_cycles(domain, pk, round, base_cycles))
+        }).await??
+    } else {
+        next_batch(history, budget, 0, |_, _| Ok(0))?
+    };
+
+    Ok((batch.pop().map(|(_, rounds)| rounds).unwrap_or_default(), more))
+}
+
+/// Proves one segment receipt per client that has at least `min_rounds` completed rounds unproven,
+/// returns `true` if rounds are left for another pass.
+async fn prove_segments<P>(
+    ctx: &Context<P>,
+    max_rounds: Option<u32>,
+    budget: &BatchBudget,
+    min_rounds: u32
+) -> Result<bool> {
+    let mapping = ctx.md_cache.load_unproven(max_rounds).await?;
+
+    let history = mapping.into_iter()
+        .filter(|(_, rounds)| rounds.len() as u32 >= min_rounds)
+        .map(|(k, rounds)| {
+            let rounds= rounds.into_iter()
+                .map(|round| Round {
+                    request: round.req.request,
+                    confirm: round.confirm_msg.map(|c| c.confirm)
+                })
+                .collect::<Vec<_>>();
+            (k, rounds)
+        })
+        .collect::<Vec<_>>();
+
+    let mut left = false;
+
+    for (pk, rounds) in history {
+        let start_seq = rounds[0].request.msg.seq;
+
+        match prove_segment(ctx, pk, rounds, budget).await {
+            Ok(more) => left |= more,
+            // one client's bad rounds must not hold back everyone else's claims
+            Err(e) => match e.downcast_ref::<CircuitError>() {
+                Some(err) => {
+                    let seq = err.seq().unwrap_or(start_seq);
+                    error!("client {}: rounds from seq {} are unprovable, left unclaimed: {}", hex::encode(pk), seq, err);
+                    ctx.md_cache.quarantine(pk, seq).await?;
+
+                    // the rounds before the rejected one are still proven
+                    left |= seq > start_seq;
                 }
-            })
-            .collect::<Vec<_>>();
+                None => return Err(e),
+            }
+        }
+    }
 
-        let tx = deopenchat.claim(claims, Bytes::from(seal))
-            .send()
+    Ok(left)
+}
+
+// proves the next segment of one client, `true` if rounds are left
+async fn prove_segment<P>(ctx: &Context<P>, pk: PublicKey, rounds: Vec<Round>, budget: &BatchBudget) -> Result<bool> {
+    let (rounds, more) = next_segment(ctx.domain, pk, rounds, budget).await?;
+
+    let segment_rounds = rounds.len() as u32;
+    let start_seq = rounds[0].request.msg.seq;
+
+    let input = Input {
+        domain: ctx.domain,
+        rounds: BTreeMap::from([(pk, rounds)])
+    };
+
+    let receipt = ctx.prover.prove_segment(input).await?;
+
+    let segment = SegmentReceipt {
+        start_seq,
+        rounds: segment_rounds,
+        receipt,
+    };
+
+    ctx.md_cache.save_segment(pk, &segment).await?;
+    info!("proved segment, client: {}, seq: {}, rounds: {}", hex::encode(pk), start_seq, segment_rounds);
+    Ok(more)
+}
+
+async fn prove_claim<P>(
+    ctx: &Context<P>,
+    max_rounds: Option<u32>,
+    budget: &BatchBudget
+) -> Result<Option<(PendingClaim, bool)>> {
+    let segments = ctx.md_cache.load_segments(max_rounds).await?;
+
+    if segments.is_empty() {
+        return Ok(None);
+    }
+
+    // the cycle budget already bounds every segment, the claim is only bound by its calldata
+    let claim_budget = BatchBudget {
+        max_cycles: None,
+        ..*budget
+    };
+
+    let (batch, more) = next_batch(segments, &claim_budget, 0, |_, _| Ok(0))?;
+
+    let receipts = batch.into_iter()
+        .flat_map(|(_, segments)| segments)
+        .map(|segment| segment.receipt)
+        .collect::<Vec<_>>();
+
+    info!("aggregating {} segments, more left: {}", receipts.len(), more);
+
+    let timer = ctx.metrics.proving_duration.start_timer();
+
+    let receipt = ctx.prover.aggregate(receipts).await?;
+
+    timer.observe_duration();
+
+    // a remote daemon may run any prover, the contract's verifier only takes groth16
+    ensure!(
+        ctx.mock_verifier || receipt.inner.groth16().is_ok(),
+        "claim receipt is not groth16, the verifier would reject it, pass --mock-verifier against a mock verifier"
+    );
+
+    let seal = risc0_ethereum_contracts::encode_seal(&receipt)?;
+    let journal = receipt.journal.bytes;
+
+    let decoded = ClaimJournal::try_from(journal.as_slice())?;
+    ensure!(decoded.segment_image_id == *deopenchat_prover::segment_image_id().as_bytes(), "journal segment image id mismatch");
+    ensure!(decoded.domain == ctx.domain, "journal domain mismatch, expected: {:?}, got: {:?}", ctx.domain, decoded.domain);
+
+    let pending = PendingClaim {
+        seal,
+        claims: decoded.claims,
+        journal,
+        tx_hash: None,
+    };
+
+    ctx.md_cache.save_pending_claim(&pending).await?;
+    info!("proved claims: {:?}", pending.claims);
+    Ok(Some((pending, more)))
+}
+
+/// How many of `claims` the chain has paid, `None` if a client's seq is neither before nor after its claim.
+async fn landed_claims<T, P>(ctx: &Context<P>, claims: &[common::Claim]) -> Result<Option<usize>>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let deopenchat = Deopenchat::new(ctx.deopenchat_contact_address, &ctx.alloy_provider);
+    let mut landed = 0;
+
+    for claim in claims {
+        let chain_seq = deopenchat.viewStatus(ctx.provider_address, FixedBytes::new(claim.pk))
+            .call()
             .await?
-            .watch()
+            ._0
+            .seq;
+
+        if chain_seq == claim.start_seq + claim.rounds - 1 {
+            landed += 1;
+        } else if chain_seq != claim.start_seq - 1 {
+            warn!("claim diverged from chain, client: {}, chain seq: {}", hex::encode(claim.pk), chain_seq);
+            return Ok(None);
+        }
+    }
+    Ok(Some(landed))
+}
+
+async fn submit_claim<T, P>(ctx: &Arc<Context<P>>, mut pending: PendingClaim) -> Result<()>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let deopenchat = Deopenchat::new(ctx.deopenchat_contact_address, &ctx.alloy_provider);
+
+    // a claim is only ever sent while the chain is still where it was proven against,
+    // so a transaction that landed before a crash or a retry is never paid twice
+    match landed_claims::<T, P>(ctx, &pending.claims).await? {
+        Some(landed) if landed == pending.claims.len() => {
+            info!("claim already landed, TX: {:?}", pending.tx_hash);
+            ctx.md_cache.settle_claim(&pending, pending.tx_hash.clone()).await?;
+            return Ok(());
+        }
+        Some(0) => (),
+        landed => {
+            warn!("claim partially landed or diverged from chain, landed: {:?}, TX: {:?}", landed, pending.tx_hash);
+            ctx.md_cache.drop_pending_claim().await?;
+            return reconcile::<T, P>(ctx).await;
+        }
+    }
+
+    let claims = pending.claims.iter()
+        .map(|c| Deopenchat::Claim {
+            clientPk: FixedBytes::new(c.pk),
+            seq: c.start_seq,
+            rounds: c.rounds,
+            unitsConsumed: c.units_consumed,
+            transcriptRoot: FixedBytes::new(c.transcript_root),
+        })
+        .collect::<Vec<_>>();
+
+    let claim_fut = async {
+        let tx = deopenchat.claim(claims, Bytes::from(pending.seal.clone()))
+            .send()
             .await?;
 
-        info!("claim TX: {}", tx);
-        ctx.md_cache.commit(&commit_claims).await?;
-        info!("commit: {:?}", commit_claims);
+        pending.tx_hash = Some(tx.tx_hash().to_string());
+        ctx.md_cache.save_pending_claim(&pending).await?;
+
+        let tx = tx.watch().await?;
+        Ok::<_, anyhow::Error>(tx)
+    };
+
+    let res = claim_fut.await;
+    ctx.metrics.claims.with_label_values(&[result_label(&res)]).inc();
+    let tx = res?;
+
+    // a reverted transaction is mined all the same, only the chain seqs tell whether it paid
+    if landed_claims::<T, P>(ctx, &pending.claims).await? != Some(pending.claims.len()) {
+        ctx.md_cache.drop_pending_claim().await?;
+        reconcile::<T, P>(ctx).await?;
+        return Err(anyhow!("claim did not land, TX: {}", tx));
+    }
 
-        let total_token_consumed = commit_claims.into_iter()
-            .map(|c| c.tokens_consumed)
-            .sum();
+    info!("claim TX: {}", tx);
 
-        ctx.accumulated_tokens.fetch_sub(total_token_consumed, Ordering::Relaxed);
+    match ctx.alloy_provider.get_transaction_receipt(tx).await {
+        Ok(Some(receipt)) => ctx.metrics.claim_gas.observe(receipt.gas_used as f64),
+        Ok(None) => (),
+        Err(e) => warn!("claim receipt error: {:?}", e),
+    }
+
+    let costs_per_kunits = *ctx.costs_per_kunits.read().unwrap();
+
+    let payout = pending.claims.iter()
+        .map(|c| units_cost(&c.units_consumed, &costs_per_kunits))
+        .fold(U256::ZERO, |acc, cost| acc + cost);
+
+    ctx.metrics.payout_wei.inc_by(payout.saturating_to::<u128>() as f64);
+
+    ctx.md_cache.settle_claim(&pending, Some(tx.to_string())).await?;
+    info!("commit: {:?}", pending.claims);
+    Ok(())
+}
+
+/// Settles rounds the contract has already paid for but the local state still holds.
+async fn reconcile<T, P>(ctx: &Context<P>) -> Result<()>
+    where
+        T: Send + Sync + Transport + Clone,
+        P: Provider<T> + 'static
+{
+    let deopenchat = Deopenchat::new(ctx.deopenchat_contact_address, &ctx.alloy_provider);
+
+    for pk in ctx.md_cache.peers() {
+        let record = deopenchat.viewStatus(ctx.provider_address, FixedBytes::new(pk))
+            .call()
+            .await?
+            ._0;
+
+        ctx.md_cache.reconcile(pk, record.seq).await?;
+    }
+    Ok(())
+}
+
+async fn expire_handler<P>(ctx: Arc<Context<P>>) -> Result<()> {
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+
+        let expired = ctx.md_cache.expire().await?;
+
+        if expired > 0 {
+            info!("voided {} expired rounds", expired);
+        }
     }
 }
 
@@ -308,7 +1049,7 @@ async fn provider_register(
     chain_endpoint: Url,
     deopenchat_contact_address: Address,
     wallet_sk: &str,
-    ktokens_cost: u32,
+    costs_per_kunits: [u32; METER_UNITS],
     endpoint: String,
     model: String,
 ) -> Result<()> {
@@ -323,7 +1064,7 @@ async fn provider_register(
     let deopenchat = Deopenchat::new(deopenchat_contact_address, alloy_provider);
 
     let tx = deopenchat.providerRegister(
-        ktokens_cost,
+        costs_per_kunits,
         endpoint,
         model
     )
@@ -342,7 +1083,15 @@ async fn daemon(
     wallet_sk: &str,
     chain_endpoint: Url,
     deopenchat_contact_address: Address,
-    commit_high_water_level: u64
+    claim_policy: ClaimPolicy,
+    batch_budget: BatchBudget,
+    prover: ProverTarget,
+    mock_verifier: bool,
+    request_timeout: Duration,
+    confirm_timeout: Duration,
+    cache_dir: PathBuf,
+    admin: Option<(SocketAddr, String)>,
+    metrics_bind_addr: Option<SocketAddr>
 ) -> Result<()> {
     let openai_config = OpenAIConfig::new().with_api_base(backend_api);
     let backend = async_openai::Client::with_config(openai_config);
@@ -357,35 +1106,96 @@ async fn daemon(
 
     let provider_address = alloy_provider.default_signer_address();
 
-    let md_cache = MetadataCache::new(&std::env::current_dir()?.join("cache"));
+    let costs_per_kunits = Deopenchat::new(deopenchat_contact_address, &alloy_provider)
+        .getProvider(provider_address)
+        .call()
+        .await?
+        ._0
+        .costPerKUnits;
+
+    let chain_id = alloy_provider.get_chain_id().await?;
+    let domain = Domain::new(chain_id, deopenchat_contact_address.into_array(), provider_address.into_array());
+
+    let store = RedbStore::open(&cache_dir)?;
+    let md_cache = MetadataCache::new(Box::new(store), request_timeout, confirm_timeout);
+
+    let unclaimed_units = md_cache.load_peers().await?;
+    info!("loaded peers, unclaimed units: {}", unclaimed_units);
 
     let ctx = Arc::new(Context {
         md_cache,
         alloy_provider,
         provider_address,
         deopenchat_contact_address,
+        domain,
         backend_client: backend,
-        accumulated_tokens: AtomicU64::new(0)
+        costs_per_kunits: std::sync::RwLock::new(costs_per_kunits),
+        metrics: Metrics::new()?,
+        prover,
+        mock_verifier,
+        claim_now: Notify::new(),
     });
 
+    reconcile(&ctx).await?;
+
     let commit_handler_fut = async {
         tokio::spawn(commit_handler(
             ctx.clone(),
-            commit_high_water_level
+            claim_policy,
+            batch_budget
         )).await?
     };
 
+    let expire_handler_fut = async {
+        tokio::spawn(expire_handler(ctx.clone())).await?
+    };
+
     let app = Router::new()
         .route("/v1/completions", get(completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/embeddings", post(embeddings))
         .route("/v1/completions/confirm", post(completions_confirm))
+        .route("/v1/completions/abandon", post(completions_abandon))
         .route("/v1/completions/seq/:pk", get(current_seq))
+        .route("/v1/completions/holes/:pk", get(holes))
+        .route("/v1/transcript/:pk/:seq", get(transcript_proof))
         .with_state(ctx.clone());
 
     let listener = tokio::net::TcpListener::bind(bind_addr).await?;
     info!("Listening on http://{}", bind_addr);
     let axum_fut = axum::serve(listener, app).into_future().map_err(|e| anyhow!(e));
 
-    tokio::try_join!(commit_handler_fut, axum_fut)?;
+    // kept off the public listener, scrapers reach it on the operator's network only
+    let metrics_fut = async {
+        let metrics_bind_addr = match metrics_bind_addr {
+            Some(addr) => addr,
+            None => return std::future::pending().await,
+        };
+
+        let app = Router::new()
+            .route("/metrics", get(metrics))
+            .with_state(ctx.clone());
+
+        let listener = tokio::net::TcpListener::bind(metrics_bind_addr).await?;
+        info!("Serving metrics on http://{}", metrics_bind_addr);
+        axum::serve(listener, app).await.map_err(|e| anyhow!(e))
+    };
+
+    let admin_fut = async {
+        let (admin_bind_addr, token) = match admin {
+            Some(admin) => admin,
+            None => return std::future::pending().await,
+        };
+
+        let app = Router::new()
+            .nest("/admin", admin::router(ctx.clone(), token));
+
+        let listener = tokio::net::TcpListener::bind(admin_bind_addr).await?;
+        info!("Serving admin API on http://{}", admin_bind_addr);
+        axum::serve(listener, app).await.map_err(|e| anyhow!(e))
+    };
+
+    tokio::try_join!(commit_handler_fut, expire_handler_fut, axum_fut, metrics_fut, admin_fut)?;
     Ok(())
 }
 
@@ -399,11 +1209,77 @@ enum SubCommand {
         backend_api: String,
 
         #[arg(long)]
-        commit_high_water_level: u64
+        commit_high_water_level: u128,
+
+        #[arg(long)]
+        client_high_water_level: Option<u128>,
+
+        #[arg(long)]
+        max_unclaimed_age_secs: Option<u64>,
+
+        #[arg(long)]
+        claim_window: Option<ClaimWindow>,
+
+        #[arg(long)]
+        min_profit_ratio: Option<f64>,
+
+        #[arg(long, default_value_t = 350000)]
+        claim_base_gas: u64,
+
+        #[arg(long, default_value_t = 40000)]
+        claim_gas_per_client: u64,
+
+        #[arg(long)]
+        max_claim_rounds: Option<u32>,
+
+        #[arg(long)]
+        max_proof_cycles: Option<u64>,
+
+        #[arg(long)]
+        max_claim_calldata: Option<usize>,
+
+        #[arg(long, default_value_t = 16)]
+        min_segment_rounds: u32,
+
+        #[arg(long, default_value_t = 600)]
+        request_timeout_secs: u64,
+
+        #[arg(long, default_value_t = 120)]
+        confirm_timeout_secs: u64,
+
+        #[arg(long, default_value = "cache")]
+        cache_dir: PathBuf,
+
+        #[arg(long)]
+        admin_bind_addr: Option<SocketAddr>,
+
+        #[arg(long)]
+        metrics_bind_addr: Option<SocketAddr>,
+
+        #[arg(long, value_enum, default_value_t = ProverKind::Groth16)]
+        prover: ProverKind,
+
+        #[arg(long)]
+        mock_verifier: bool,
+
+        #[arg(long)]
+        remote_prover: Option<Url>
     },
     ProviderRegister {
         #[arg(long)]
-        ktokens_cost: u32,
+        input_ktokens_cost: u32,
+
+        #[arg(long)]
+        output_ktokens_cost: u32,
+
+        #[arg(long, default_value_t = 0)]
+        kimages_cost: u32,
+
+        #[arg(long, default_value_t = 0)]
+        kaudio_seconds_cost: u32,
+
+        #[arg(long, default_value_t = 0)]
+        kdocuments_cost: u32,
 
         #[arg(long)]
         endpoint: String,
@@ -441,18 +1317,84 @@ fn exec(args: Args) -> Result<()> {
             bind_addr,
             backend_api,
             commit_high_water_level,
+            client_high_water_level,
+            max_unclaimed_age_secs,
+            claim_window,
+            min_profit_ratio,
+            claim_base_gas,
+            claim_gas_per_client,
+            max_claim_rounds,
+            max_proof_cycles,
+            max_claim_calldata,
+            min_segment_rounds,
+            request_timeout_secs,
+            confirm_timeout_secs,
+            cache_dir,
+            admin_bind_addr,
+            metrics_bind_addr,
+            prover,
+            mock_verifier,
+            remote_prover,
         } => {
+            // tokens are kept out of argv, where every local user can read them
+            let prover = match remote_prover {
+                Some(endpoint) => ProverTarget::Remote(RemoteProver::new(endpoint, std::env::var("DEOPENCHAT_REMOTE_PROVER_TOKEN").ok().filter(|t| !t.is_empty()))),
+                None => {
+                    ensure!(
+                        prover == ProverKind::Groth16 || mock_verifier,
+                        "--prover {:?} claims are rejected by the verifier, pass --mock-verifier against a mock verifier", prover
+                    );
+                    ProverTarget::Local(Arc::from(prover.build()))
+                }
+            };
+
+            let admin = match admin_bind_addr {
+                Some(addr) => {
+                    let token = std::env::var("DEOPENCHAT_ADMIN_TOKEN").unwrap_or_default();
+                    ensure!(!token.is_empty(), "--admin-bind-addr requires DEOPENCHAT_ADMIN_TOKEN");
+                    Some((addr, token))
+                }
+                None => None,
+            };
+
+            let claim_policy = ClaimPolicy {
+                high_water_level: commit_high_water_level,
+                client_high_water_level,
+                max_age: max_unclaimed_age_secs.map(Duration::from_secs),
+                window: claim_window,
+                min_profit_ratio,
+                base_gas: claim_base_gas,
+                gas_per_client: claim_gas_per_client,
+                max_rounds: max_claim_rounds,
+            };
+
             rt.block_on(daemon(
                 bind_addr,
                 backend_api,
                 &args.wallet_sk,
                 args.chain_endpoint,
                 args.deopenchat_contact_address,
-                commit_high_water_level
+                claim_policy,
+                BatchBudget {
+                    max_cycles: max_proof_cycles,
+                    max_calldata: max_claim_calldata,
+                    min_segment_rounds,
+                },
+                prover,
+                mock_verifier,
+                Duration::from_secs(request_timeout_secs),
+                Duration::from_secs(confirm_timeout_secs),
+                cache_dir,
+                admin,
+                metrics_bind_addr
             ))
         }
         SubCommand::ProviderRegister {
-            ktokens_cost,
+            input_ktokens_cost,
+            output_ktokens_cost,
+            kimages_cost,
+            kaudio_seconds_cost,
+            kdocuments_cost,
             endpoint,
             model
         } => {
@@ -460,7 +1402,7 @@ fn exec(args: Args) -> Result<()> {
                 args.chain_endpoint,
                 args.deopenchat_contact_address,
                 &args.wallet_sk,
-                ktokens_cost,
+                [input_ktokens_cost, output_ktokens_cost, kimages_cost, kaudio_seconds_cost, kdocuments_cost],
                 endpoint,
                 model
             ))
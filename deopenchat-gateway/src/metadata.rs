@@ -1,9 +1,14 @@
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use common::{CompletionsReq, CompletionsResp, ConfirmReq, PublicKey};
-use anyhow::{ensure, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use common::api::{ApiRequest, ApiResponse};
+use common::merkle::{self, MerkleProof};
+use common::{AbandonReq, CompletionsReq, CompletionsResp, ConfirmMsg, ConfirmReq, Digest, PublicKey, METER_UNITS, SEQ_WINDOW};
+use anyhow::{anyhow, ensure, Result};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use deopenchat_prover::Receipt;
+use crate::store::{Batch, MetadataStore, Table};
 
 #[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RoundState {
@@ -13,36 +18,363 @@ pub enum RoundState {
 }
 
 #[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InflightRound {
+    pub state: RoundState,
+    // unix seconds after which the round is voided
+    pub deadline: u64,
+    // worst-case units held against the balance until the round completes
+    #[serde(default)]
+    pub reserved: [u64; METER_UNITS],
+    // unix seconds the round reached the gateway
+    #[serde(default)]
+    pub requested_at: u64,
+}
+
+fn history_key(key_str: &str, seq: u32) -> String {
+    format!("{}-{}", key_str, seq)
+}
+
+fn deadline(timeout: Duration) -> u64 {
+    (SystemTime::now() + timeout).duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct PeerStatus {
+    // highest requested seq
     pub seq: u32,
     pub commit_seq: u32,
-    pub state: RoundState,
+    // every round up to here is completed
+    pub confirmed_seq: u32,
+    // every round up to here is in a segment receipt, never behind commit_seq
+    #[serde(default)]
+    pub proven_seq: u32,
+    // rounds above confirmed_seq, within SEQ_WINDOW
+    pub inflight: BTreeMap<u32, InflightRound>,
+    // confirmed usage per unit not yet claimed on chain
+    pub unclaimed: [u64; METER_UNITS],
+    // unix seconds the round after commit_seq was requested, none while every confirmed round is claimed
+    #[serde(default)]
+    pub unclaimed_since: Option<u64>,
+    // first round the circuit rejects, none of the rounds from here is proven until the chain moves past it
+    #[serde(default)]
+    pub unprovable_seq: Option<u32>,
+}
+
+impl PeerStatus {
+    pub fn from_chain(seq: u32) -> Self {
+        PeerStatus {
+            seq,
+            commit_seq: seq,
+            confirmed_seq: seq,
+            proven_seq: seq,
+            inflight: BTreeMap::new(),
+            unclaimed: [0; METER_UNITS],
+            unclaimed_since: None,
+            unprovable_seq: None,
+        }
+    }
+
+    // statuses written before segments were proven carry no proven_seq
+    fn proven_seq(&self) -> u32 {
+        self.proven_seq.max(self.commit_seq)
+    }
+
+    // rounds from unprovable_seq on are never claimed, so their usage isn't billed
+    fn billable(&self, seq: u32) -> bool {
+        match self.unprovable_seq {
+            Some(unprovable_seq) => seq < unprovable_seq,
+            None => true,
+        }
+    }
+
+    /// Seqs below the highest request that never reached the gateway, only the client can close them.
+    pub fn holes(&self) -> Vec<u32> {
+        (self.confirmed_seq + 1..self.seq)
+            .filter(|seq| !self.inflight.contains_key(seq))
+            .collect()
+    }
+
+    /// Units the client's balance has to cover, confirmed usage plus what open rounds may still bill.
+    pub fn held(&self) -> [u64; METER_UNITS] {
+        let mut held = self.unclaimed;
+
+        for round in self.inflight.values() {
+            for (total, amount) in held.iter_mut().zip(round.reserved) {
+                *total += amount;
+            }
+        }
+        held
+    }
+
+    fn transit(&mut self, seq: u32, from: &[RoundState], to: RoundState, deadline: u64) -> Result<()> {
+        let round = self.inflight.get_mut(&seq).ok_or_else(|| anyhow::anyhow!("round {} not in flight", seq))?;
+        ensure!(from.contains(&round.state), "round {} in unexpected state", seq);
+
+        round.state = to;
+        round.deadline = deadline;
+
+        // a completed round bills its confirmed usage through `unclaimed`
+        if to == RoundState::Completed {
+            round.reserved = [0; METER_UNITS];
+        }
+
+        self.advance();
+        Ok(())
+    }
+
+    fn advance(&mut self) {
+        while let Some(round) = self.inflight.get(&(self.confirmed_seq + 1)).filter(|r| r.state == RoundState::Completed) {
+            self.unclaimed_since = self.unclaimed_since.or(Some(round.requested_at));
+            self.inflight.remove(&(self.confirmed_seq + 1));
+            self.confirmed_seq += 1;
+        }
+    }
 }
 
+// format of the stored history, rounds of version 0 were signed without a `Domain`
+const HISTORY_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
 pub struct RoundData {
+    #[serde(default)]
+    pub version: u32,
     pub seq: u32,
-    pub req: CompletionsReq<async_openai::types::CreateCompletionRequest>,
-    pub resp: CompletionsResp<async_openai::types::CreateCompletionResponse>,
+    // unix seconds the round reached the gateway, zero for rounds stored before it was kept
+    #[serde(default)]
+    pub requested_at: u64,
+    pub req: CompletionsReq<ApiRequest>,
+    // none if the backend failed or the round was abandoned before the response
+    pub resp: Option<CompletionsResp<ApiResponse>>,
+    // none if the round was voided without the client's confirm
     pub confirm_msg: Option<ConfirmReq>,
 }
 
+impl RoundData {
+    /// Confirm message the round is proven with.
+    pub fn confirm_msg(&self) -> ConfirmMsg {
+        match &self.confirm_msg {
+            Some(confirm) => confirm.confirm.msg,
+            None => ConfirmMsg::void(self.seq),
+        }
+    }
+}
+
+/// Leaves of a claimed range, kept after the history is pruned so rounds stay provable.
+#[derive(Serialize, Deserialize)]
+pub struct Transcript {
+    pub start_seq: u32,
+    pub root: Digest,
+    pub leaves: Vec<Digest>,
+}
+
+/// Claims settled together, `tx_hash` is none for claims recovered by `reconcile`.
+#[derive(Serialize, Deserialize)]
+pub struct ClaimRecord {
+    pub time: u64,
+    pub tx_hash: Option<String>,
+    pub claims: Vec<common::Claim>,
+}
+
+const PENDING_CLAIM_KEY: &str = "pending";
+
+/// Proven claim not yet settled, persisted so a restart resumes it instead of proving again.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingClaim {
+    pub seal: Vec<u8>,
+    pub journal: Vec<u8>,
+    pub claims: Vec<common::Claim>,
+    // set once the transaction is sent
+    pub tx_hash: Option<String>,
+}
+
+/// Succinct receipt over the rounds `start_seq..start_seq + rounds` of one client.
+#[derive(Serialize, Deserialize)]
+pub struct SegmentReceipt {
+    pub start_seq: u32,
+    pub rounds: u32,
+    pub receipt: Receipt,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TranscriptProof {
+    pub seq: u32,
+    pub start_seq: u32,
+    pub rounds: u32,
+    pub transcript_root: Digest,
+    pub leaf: Digest,
+    pub proof: MerkleProof,
+}
+
 pub struct MetadataCache {
-    round_status_dir: PathBuf,
-    history_dir: PathBuf,
+    store: Arc<dyn MetadataStore>,
+    // backend deadline of a requested round
+    request_timeout: Duration,
+    // client deadline of a responded round
+    confirm_timeout: Duration,
     locks: std::sync::Mutex<HashMap<PublicKey, Arc<tokio::sync::RwLock<()>>>>
 }
 
 impl MetadataCache {
-    pub fn new(cache_dir: &Path) -> Self {
+    pub fn new(store: Box<dyn MetadataStore>, request_timeout: Duration, confirm_timeout: Duration) -> Self {
         Self {
-            round_status_dir: cache_dir.join("status"),
-            history_dir: cache_dir.join("history"),
+            store: Arc::from(store),
+            request_timeout,
+            confirm_timeout,
             locks: std::sync::Mutex::new(HashMap::new())
         }
     }
 
-    pub async fn update_from_chain(&self, key: PublicKey, status: PeerStatus) -> Result<()> {
+    // a commit syncs to disk, so it runs off the async workers
+    async fn write(&self, batch: Batch) -> Result<()> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.write(batch)).await?
+    }
+
+    /// Registers every peer found in the status store, returns their unclaimed units.
+    pub async fn load_peers(&self) -> Result<u64> {
+        let mut unclaimed_units = 0;
+
+        for key_str in self.store.keys(Table::Status)? {
+            let key: PublicKey = match hex::decode(&key_str).ok().and_then(|k| k.try_into().ok()) {
+                Some(key) => key,
+                None => {
+                    warn!("unexpected status entry: {}", key_str);
+                    continue;
+                }
+            };
+
+            let mut s = self.status(&key_str)?;
+
+            // the circuit only verifies signatures over the domain, older rounds can't be claimed anymore
+            if let Some(seq) = self.legacy_seq(&key_str, &s)? {
+                warn!("client {}: rounds from seq {} were signed before domain separation and are left unclaimed", key_str, seq);
+
+                let mut batch = Batch::default();
+                self.drop_segments_from(&key_str, &mut s, seq, &mut batch)?;
+                self.mark_unprovable(&key_str, &mut s, seq)?;
+                batch.put(Table::Status, key_str.clone(), &s)?;
+                self.write(batch).await?;
+            }
+
+            unclaimed_units += s.unclaimed.iter().sum::<u64>();
+
+            self.locks.lock().unwrap()
+                .entry(key)
+                .or_insert_with(|| Arc::new(tokio::sync::RwLock::new(())));
+        }
+
+        Ok(unclaimed_units)
+    }
+
+    // first unclaimed round stored before `HISTORY_VERSION`
+    fn legacy_seq(&self, key_str: &str, s: &PeerStatus) -> Result<Option<u32>> {
+        let end = s.unprovable_seq.map_or(s.seq, |seq| seq.saturating_sub(1));
+
+        for seq in s.commit_seq + 1..=end {
+            if let Some(rd) = self.store.get::<RoundData>(Table::History, &history_key(key_str, seq))? {
+                if rd.version < HISTORY_VERSION {
+                    return Ok(Some(seq));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // segment receipts reaching `seq` or past it, proven by the guest of another release
+    fn drop_segments_from(&self, key_str: &str, s: &mut PeerStatus, seq: u32, batch: &mut Batch) -> Result<()> {
+        let index: Vec<(u32, u32)> = self.store.get(Table::Segments, key_str)?.unwrap_or_default();
+        let (kept, dropped): (Vec<_>, Vec<_>) = index.into_iter().partition(|&(start_seq, rounds)| start_seq + rounds - 1 < seq);
+
+        for (start_seq, _) in dropped {
+            batch.remove(Table::Segments, history_key(key_str, start_seq));
+        }
+
+        s.proven_seq = s.proven_seq.min(seq - 1);
+        batch.put(Table::Segments, key_str, &kept)
+    }
+
+    fn status(&self, key_str: &str) -> Result<PeerStatus> {
+        self.store.get(Table::Status, key_str)?.ok_or_else(|| anyhow!("no status for {}", key_str))
+    }
+
+    fn round(&self, key_str: &str, seq: u32) -> Result<RoundData> {
+        self.store.get(Table::History, &history_key(key_str, seq))?.ok_or_else(|| anyhow!("round {} not found", seq))
+    }
+
+    pub fn peers(&self) -> Vec<PublicKey> {
+        self.locks.lock().unwrap().keys().copied().collect()
+    }
+
+    pub async fn statuses(&self) -> Result<Vec<(PublicKey, PeerStatus)>> {
+        let mut out = Vec::new();
+
+        for key in self.peers() {
+            if let Some(s) = self.load_status(key).await? {
+                out.push((key, s));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Catches `commit_seq` up with the seq already paid on chain, returns the units settled.
+    pub async fn reconcile(&self, key: PublicKey, chain_seq: u32) -> Result<u64> {
+        let key_str = hex::encode(&key);
+
+        let s = match self.load_status(key).await? {
+            Some(s) => s,
+            None => return Ok(0),
+        };
+
+        if chain_seq < s.commit_seq {
+            warn!("chain seq {} behind commit seq {}, client: {}", chain_seq, s.commit_seq, key_str);
+        }
+
+        if chain_seq <= s.commit_seq {
+            return Ok(0);
+        }
+
+        // rounds paid on chain that were never confirmed here, nothing left to prove
+        if chain_seq > s.confirmed_seq {
+            warn!("chain seq {} ahead of confirmed seq {}, client: {}", chain_seq, s.confirmed_seq, key_str);
+            return self.update_from_chain(key, chain_seq).await;
+        }
+
+        let mut units_consumed = [0u64; METER_UNITS];
+        let mut leaves = Vec::new();
+
+        for seq in s.commit_seq + 1..=chain_seq {
+            let rd = self.load_round(key, seq).await?;
+            let confirm_msg = rd.confirm_msg();
+
+            for (total, amount) in units_consumed.iter_mut().zip(confirm_msg.usage) {
+                *total += amount as u64;
+            }
+            leaves.push(merkle::leaf(rd.req.request.msg, confirm_msg));
+        }
+
+        let claim = common::Claim {
+            pk: key,
+            start_seq: s.commit_seq + 1,
+            rounds: chain_seq - s.commit_seq,
+            units_consumed,
+            transcript_root: merkle::root(&leaves),
+        };
+
+        info!("reconciled claim: {:?}", claim);
+        self.commit(&[claim], None).await?;
+        Ok(units_consumed.iter().sum())
+    }
+
+    /// Takes every round up to `chain_seq` as paid, returns the unclaimed units settled with them.
+    ///
+    /// Confirmed rounds are left to `commit`, which keeps their transcript, and open rounds past
+    /// `chain_seq` stay in flight.
+    pub async fn update_from_chain(&self, key: PublicKey, chain_seq: u32) -> Result<u64> {
         let key_str = hex::encode(&key);
         let lock= {
             let mut lg = self.locks.lock().unwrap();
@@ -51,12 +383,61 @@ impl MetadataCache {
 
         let _lg = lock.write().await;
 
-        let buf = serde_json::to_vec(&status)?;
-        cacache::write(&self.round_status_dir, key_str, &buf).await?;
-        Ok(())
+        let mut settled = 0;
+        let mut batch = Batch::default();
+
+        let mut status = match self.store.get::<PeerStatus>(Table::Status, &key_str)? {
+            None => PeerStatus::from_chain(chain_seq),
+            Some(s) if chain_seq <= s.confirmed_seq => return Ok(0),
+            Some(mut s) => {
+                if s.unprovable_seq.is_some_and(|seq| seq <= chain_seq) {
+                    s.unprovable_seq = None;
+                }
+
+                // completed rounds past the paid ones still bill their usage
+                let mut unclaimed = [0u64; METER_UNITS];
+
+                for (&seq, round) in s.inflight.range(chain_seq + 1..) {
+                    if round.state == RoundState::Completed && s.billable(seq) {
+                        let usage = self.round(&key_str, seq)?.confirm_msg().usage;
+
+                        for (total, amount) in unclaimed.iter_mut().zip(usage) {
+                            *total += amount as u64;
+                        }
+                    }
+                }
+
+                settled = s.unclaimed.iter().sum::<u64>().saturating_sub(unclaimed.iter().sum());
+
+                // paid elsewhere, nothing proves or serves these rounds anymore
+                for seq in s.commit_seq + 1..=chain_seq {
+                    batch.remove(Table::History, history_key(&key_str, seq));
+                }
+
+                s.inflight = s.inflight.split_off(&(chain_seq + 1));
+                s.unclaimed = unclaimed;
+                s.seq = s.seq.max(chain_seq);
+                s.commit_seq = chain_seq;
+                s.confirmed_seq = chain_seq;
+                s.proven_seq = chain_seq;
+                s.unclaimed_since = None;
+                s.advance();
+                s
+            }
+        };
+
+        self.prune_segments(&key_str, &mut status, &mut batch)?;
+        batch.put(Table::Status, key_str, &status)?;
+        self.write(batch).await?;
+        Ok(settled)
     }
 
-    pub async fn req(&self, req: &CompletionsReq<async_openai::types::CreateCompletionRequest>) -> Result<()> {
+    /// Opens a round, `reserve` gets the units already held and returns what the round holds on top.
+    pub async fn req(
+        &self,
+        req: &CompletionsReq<ApiRequest>,
+        reserve: impl FnOnce(&[u64; METER_UNITS]) -> Result<[u64; METER_UNITS]>
+    ) -> Result<()> {
         let key = req.pk;
         let key_str = hex::encode(&key);
 
@@ -65,44 +446,71 @@ impl MetadataCache {
             lg.entry(key).or_insert_with(|| Arc::new(tokio::sync::RwLock::new(()))).clone()
         };
 
-        let _lg = lock.write().await;
-        let buf = match cacache::read(&self.round_status_dir, &key_str).await {
-            Ok(buf) => buf,
-            Err(cacache::Error::EntryNotFound(_, _)) => {
-                ensure!(req.request.msg.seq == 1);
-
-                let round = PeerStatus {
-                    seq: 1,
-                    commit_seq: 0,
-                    state: RoundState::Requested,
-                };
-
-                let buf = serde_json::to_vec(&round)?;
-                cacache::write(&self.round_status_dir, key_str, &buf).await?;
-                return Ok(());
-            }
-            Err(e) => return Err(e.into()),
+        let res = {
+            let _lg = lock.write().await;
+            self.open_round(&key_str, req, reserve).await
         };
 
-        let curr: PeerStatus = serde_json::from_slice(&buf)?;
-        ensure!(curr.state == RoundState::Completed);
-        ensure!(curr.seq + 1 == req.request.msg.seq);
+        if res.is_err() {
+            self.release_unknown(&key, &key_str, lock)?;
+        }
+        res
+    }
+
+    // a key whose first round was refused has no status, its lock goes again
+    fn release_unknown(&self, key: &PublicKey, key_str: &str, lock: Arc<tokio::sync::RwLock<()>>) -> Result<()> {
+        let mut lg = self.locks.lock().unwrap();
 
-        let round = PeerStatus {
-            seq: req.request.msg.seq,
-            commit_seq: curr.commit_seq,
+        // only the map and us hold it, no other request waits on it
+        if Arc::strong_count(&lock) == 2 && self.store.get_raw(Table::Status, key_str)?.is_none() {
+            lg.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn open_round(
+        &self,
+        key_str: &str,
+        req: &CompletionsReq<ApiRequest>,
+        reserve: impl FnOnce(&[u64; METER_UNITS]) -> Result<[u64; METER_UNITS]>
+    ) -> Result<()> {
+        let mut curr: PeerStatus = self.store.get(Table::Status, key_str)?.unwrap_or_default();
+
+        let seq = req.request.msg.seq;
+        ensure!(seq > curr.confirmed_seq && seq <= curr.confirmed_seq + SEQ_WINDOW, "seq {} out of window", seq);
+        ensure!(!curr.inflight.contains_key(&seq), "seq {} already requested", seq);
+
+        // decided under the lock, concurrent rounds can't spend the same balance
+        let reserved = reserve(&curr.held())?;
+        let requested_at = now();
+
+        curr.inflight.insert(seq, InflightRound {
             state: RoundState::Requested,
+            deadline: deadline(self.request_timeout),
+            reserved,
+            requested_at,
+        });
+        curr.seq = curr.seq.max(seq);
+
+        let rd = RoundData {
+            version: HISTORY_VERSION,
+            seq,
+            requested_at,
+            req: req.clone(),
+            resp: None,
+            confirm_msg: None,
         };
 
-        let buf = serde_json::to_vec(&round)?;
-        cacache::write(&self.round_status_dir, key_str, &buf).await?;
-        Ok(())
+        let mut batch = Batch::default();
+        batch.put(Table::History, history_key(key_str, seq), &rd)?;
+        batch.put(Table::Status, key_str, &curr)?;
+        self.write(batch).await
     }
 
     pub async fn resp(
         &self,
-        req: &CompletionsReq<async_openai::types::CreateCompletionRequest>,
-        resp: &CompletionsResp<async_openai::types::CreateCompletionResponse>
+        req: &CompletionsReq<ApiRequest>,
+        resp: &CompletionsResp<ApiResponse>
     ) -> Result<()> {
         let key = req.pk;
         let key_str = hex::encode(&key);
@@ -114,24 +522,18 @@ impl MetadataCache {
 
         let _guard = lock.write().await;
 
-        let buf = cacache::read(&self.round_status_dir, &key_str).await?;
-        let mut curr_round: PeerStatus = serde_json::from_slice(&buf)?;
+        let mut curr = self.status(&key_str)?;
 
-        ensure!(curr_round.state == RoundState::Requested);
-        ensure!(curr_round.seq == req.request.msg.seq);
+        let seq = req.request.msg.seq;
+        curr.transit(seq, &[RoundState::Requested], RoundState::WaitingConfirm, deadline(self.confirm_timeout))?;
 
-        let rd = RoundData {
-            seq: req.request.msg.seq,
-            req: req.clone(),
-            resp: resp.clone(),
-            confirm_msg: None,
-        };
+        let mut rd = self.round(&key_str, seq)?;
+        rd.resp = Some(resp.clone());
 
-        cacache::write(&self.history_dir, format!("{}-{}", key_str, curr_round.seq), &serde_json::to_vec(&rd)?).await?;
-
-        curr_round.state = RoundState::WaitingConfirm;
-        cacache::write(&self.round_status_dir, key_str, &serde_json::to_vec(&curr_round)?).await?;
-        Ok(())
+        let mut batch = Batch::default();
+        batch.put(Table::History, history_key(&key_str, seq), &rd)?;
+        batch.put(Table::Status, key_str, &curr)?;
+        self.write(batch).await
     }
 
     pub async fn confirm(&self, confirm: &ConfirmReq) -> Result<()> {
@@ -145,22 +547,57 @@ impl MetadataCache {
 
         let _guard = lock.write().await;
 
-        let buf = cacache::read(&self.round_status_dir, &key_str).await?;
-        let mut curr_round: PeerStatus = serde_json::from_slice(&buf)?;
-        ensure!(curr_round.state == RoundState::WaitingConfirm);
-        ensure!(curr_round.seq == confirm.confirm.msg.seq);
+        let mut curr = self.status(&key_str)?;
+
+        let seq = confirm.confirm.msg.seq;
+        curr.transit(seq, &[RoundState::WaitingConfirm], RoundState::Completed, 0)?;
+
+        if curr.billable(seq) {
+            for (total, amount) in curr.unclaimed.iter_mut().zip(confirm.confirm.msg.usage) {
+                *total += amount as u64;
+            }
+        }
 
-        let buf = cacache::read(&self.history_dir, &format!("{}-{}", key_str, confirm.confirm.msg.seq)).await?;
-        let mut rd: RoundData = serde_json::from_slice(&buf)?;
+        let mut rd = self.round(&key_str, seq)?;
         rd.confirm_msg = Some(confirm.clone());
-        cacache::write(&self.history_dir, &format!("{}-{}", key_str, curr_round.seq), &serde_json::to_vec(&rd)?).await?;
 
-        curr_round.state = RoundState::Completed;
-        cacache::write(&self.round_status_dir, key_str, &serde_json::to_vec(&curr_round)?).await?;
-        Ok(())
+        let mut batch = Batch::default();
+        batch.put(Table::History, history_key(&key_str, seq), &rd)?;
+        batch.put(Table::Status, key_str, &curr)?;
+        self.write(batch).await
     }
 
-    pub async fn load_round(&self, key: PublicKey, seq: u32) -> Result<RoundData> {
+    /// Closes a round that failed on the backend side, it bills nothing.
+    pub async fn void(&self, key: PublicKey, seq: u32) -> Result<()> {
+        let key_str = hex::encode(&key);
+
+        let lock= {
+            let lg = self.locks.lock().unwrap();
+            lg.get(&key).ok_or_else(|| anyhow::anyhow!("Key not found"))?.clone()
+        };
+
+        let _guard = lock.write().await;
+
+        let mut curr = self.status(&key_str)?;
+
+        match curr.inflight.get(&seq) {
+            Some(r) if r.state != RoundState::Completed => (),
+            // already confirmed, abandoned or expired
+            _ => return Ok(()),
+        }
+
+        curr.transit(seq, &[RoundState::Requested, RoundState::WaitingConfirm], RoundState::Completed, 0)?;
+
+        let mut batch = Batch::default();
+        batch.put(Table::Status, key_str, &curr)?;
+        self.write(batch).await
+    }
+
+    /// Closes a round on the client's signed void confirm, the round may not have reached the gateway.
+    ///
+    /// An answered round is only closed by the client's real confirm or its deadline.
+    pub async fn abandon(&self, abandon: &AbandonReq<ApiRequest>) -> Result<()> {
+        let key = abandon.req.pk;
         let key_str = hex::encode(&key);
 
         let lock= {
@@ -168,11 +605,106 @@ impl MetadataCache {
             lg.entry(key).or_insert_with(|| Arc::new(tokio::sync::RwLock::new(()))).clone()
         };
 
-        let _guard = lock.read().await;
+        let _guard = lock.write().await;
+        let mut curr: PeerStatus = self.store.get(Table::Status, &key_str)?.unwrap_or_default();
+
+        let seq = abandon.req.request.msg.seq;
+        let confirm = ConfirmReq {
+            pk: key,
+            confirm: abandon.confirm.clone(),
+        };
+
+        if seq <= curr.confirmed_seq {
+            return Ok(());
+        }
+
+        let mut rd = match curr.inflight.get(&seq).map(|r| r.state) {
+            Some(RoundState::Completed) => return Ok(()),
+            Some(RoundState::WaitingConfirm) => return Err(anyhow!("round {} answered, confirm it instead", seq)),
+            Some(RoundState::Requested) => self.round(&key_str, seq)?,
+            None => {
+                ensure!(seq <= curr.confirmed_seq + SEQ_WINDOW, "seq {} out of window", seq);
+
+                let requested_at = now();
+
+                curr.inflight.insert(seq, InflightRound {
+                    state: RoundState::Requested,
+                    deadline: 0,
+                    reserved: [0; METER_UNITS],
+                    requested_at,
+                });
+                curr.seq = curr.seq.max(seq);
+
+                RoundData {
+                    version: HISTORY_VERSION,
+                    seq,
+                    requested_at,
+                    req: abandon.req.clone(),
+                    resp: None,
+                    confirm_msg: None,
+                }
+            }
+        };
+
+        curr.transit(seq, &[RoundState::Requested], RoundState::Completed, 0)?;
+
+        rd.confirm_msg = Some(confirm);
+
+        let mut batch = Batch::default();
+        batch.put(Table::History, history_key(&key_str, seq), &rd)?;
+        batch.put(Table::Status, key_str, &curr)?;
+        self.write(batch).await
+    }
+
+    /// Voids every round past its deadline, returns how many were voided.
+    pub async fn expire(&self) -> Result<usize> {
+        let keys = self.locks.lock().unwrap().clone();
+        let now = now();
+        let mut expired = 0;
+
+        for (key, lock) in keys {
+            let key_str = hex::encode(&key);
+            let _guard = lock.write().await;
+
+            let mut curr: PeerStatus = match self.store.get(Table::Status, &key_str)? {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let seqs = curr.inflight.iter()
+                .filter(|(_, r)| r.state != RoundState::Completed && r.deadline < now)
+                .map(|(&seq, _)| seq)
+                .collect::<Vec<_>>();
+
+            if seqs.is_empty() {
+                continue;
+            }
+
+            for &seq in &seqs {
+                warn!("round expired, client: {}, seq: {}", key_str, seq);
+                curr.transit(seq, &[RoundState::Requested, RoundState::WaitingConfirm], RoundState::Completed, 0)?;
+            }
+
+            expired += seqs.len();
+
+            let mut batch = Batch::default();
+            batch.put(Table::Status, key_str, &curr)?;
+            self.write(batch).await?;
+        }
+
+        Ok(expired)
+    }
+
+    pub async fn load_round(&self, key: PublicKey, seq: u32) -> Result<RoundData> {
+        let key_str = hex::encode(&key);
 
-        let buf = cacache::read(&self.history_dir, &format!("{}-{}", key_str, seq)).await?;
-        let rd: RoundData = serde_json::from_slice(&buf)?;
-        Ok(rd)
+        let lock= {
+            let lg = self.locks.lock().unwrap();
+            lg.get(&key).ok_or_else(|| anyhow!("Key not found"))?.clone()
+        };
+
+        let _guard = lock.read().await;
+        self.round(&key_str, seq)
     }
 
     pub async fn load_status(&self, key: PublicKey) -> Result<Option<PeerStatus>> {
@@ -187,36 +719,49 @@ impl MetadataCache {
         };
 
         let _guard = lock.read().await;
+        self.store.get(Table::Status, &key_str)
+    }
+
+    /// Unix seconds of the oldest round not yet claimed.
+    pub async fn oldest_unclaimed(&self) -> Result<Option<u64>> {
+        let oldest = self.statuses().await?
+            .into_iter()
+            .filter(|(_, s)| s.confirmed_seq > s.commit_seq)
+            .filter_map(|(_, s)| s.unclaimed_since)
+            .min();
 
-        let buf = cacache::read(&self.round_status_dir, &key_str).await?;
-        let curr_round: PeerStatus = serde_json::from_slice(&buf)?;
-        Ok(Some(curr_round))
+        Ok(oldest)
     }
 
-    pub async fn load_all_history(&self) -> Result<HashMap<PublicKey, Vec<RoundData>>> {
-        // todo load old peers
-        let keys = self.locks.lock().unwrap().clone();
-        let mut out = HashMap::new();
+    /// Completed rounds per client not yet in a segment receipt, at most `max_rounds` in total.
+    pub async fn load_unproven(&self, max_rounds: Option<u32>) -> Result<BTreeMap<PublicKey, Vec<RoundData>>> {
+        // the budget goes to clients in key order, like claims in the journal
+        let keys = self.locks.lock().unwrap().clone().into_iter().collect::<BTreeMap<_, _>>();
+        let mut out = BTreeMap::new();
+        let mut budget = max_rounds.unwrap_or(u32::MAX);
 
         for (key, lock) in keys {
             let key_str = hex::encode(&key);
             let _guard = lock.read().await;
 
-            let buf = cacache::read(&self.round_status_dir, &key_str).await?;
-            let s: PeerStatus = serde_json::from_slice(&buf)?;
+            let s: PeerStatus = match self.store.get(Table::Status, &key_str)? {
+                Some(s) => s,
+                None => continue,
+            };
 
             let mut rounds = Vec::new();
+            let provable_seq = s.unprovable_seq.map_or(s.confirmed_seq, |seq| s.confirmed_seq.min(seq.saturating_sub(1)));
 
-            for seq in s.commit_seq + 1..=s.seq {
-                let buf = match cacache::read(&self.history_dir, &format!("{}-{}", key_str, seq)).await {
-                    Ok(buf) => buf,
-                    Err(_) => break,
-                };
-                let rd: RoundData = serde_json::from_slice(&buf)?;
-                rounds.push(rd);
+            // only the gap-free confirmed prefix can be proven
+            for seq in s.proven_seq() + 1..=provable_seq.min(s.proven_seq().saturating_add(budget)) {
+                match self.store.get(Table::History, &history_key(&key_str, seq))? {
+                    Some(rd) => rounds.push(rd),
+                    None => break,
+                }
             }
 
             if !rounds.is_empty() {
+                budget -= rounds.len() as u32;
                 out.insert(key, rounds);
             }
         }
@@ -224,28 +769,616 @@ impl MetadataCache {
         Ok(out)
     }
 
-    pub async fn commit(&self, claims: &[common::Claim]) -> Result<()> {
+    /// Stops proving the rounds of `key` from `seq` on, the circuit rejects the round at `seq`.
+    pub async fn quarantine(&self, key: PublicKey, seq: u32) -> Result<()> {
+        let key_str = hex::encode(&key);
+
+        let lock = {
+            let lg = self.locks.lock().unwrap();
+            lg.get(&key).ok_or_else(|| anyhow!("Key not found"))?.clone()
+        };
+
+        let _guard = lock.write().await;
+
+        let mut s = self.status(&key_str)?;
+        self.mark_unprovable(&key_str, &mut s, seq)?;
+
+        let mut batch = Batch::default();
+        batch.put(Table::Status, key_str, &s)?;
+        self.write(batch).await
+    }
+
+    // takes the rounds from `seq` on out of the bill, nothing of them is ever claimed
+    fn mark_unprovable(&self, key_str: &str, s: &mut PeerStatus, seq: u32) -> Result<()> {
+        let end = s.unprovable_seq.map_or(s.seq, |curr| curr.saturating_sub(1));
+
+        // voided and open rounds read as void confirms, they bill nothing
+        for seq in seq..=end {
+            if let Some(rd) = self.store.get::<RoundData>(Table::History, &history_key(key_str, seq))? {
+                for (total, amount) in s.unclaimed.iter_mut().zip(rd.confirm_msg().usage) {
+                    *total = total.saturating_sub(amount as u64);
+                }
+            }
+        }
+
+        if seq <= s.commit_seq + 1 {
+            s.unclaimed_since = None;
+        }
+
+        s.unprovable_seq = Some(s.unprovable_seq.map_or(seq, |curr| curr.min(seq)));
+        Ok(())
+    }
+
+    /// Stores a segment receipt proven over the rounds following `proven_seq`.
+    pub async fn save_segment(&self, key: PublicKey, segment: &SegmentReceipt) -> Result<()> {
+        let key_str = hex::encode(&key);
+
+        let lock = {
+            let lg = self.locks.lock().unwrap();
+            lg.get(&key).ok_or_else(|| anyhow!("Key not found"))?.clone()
+        };
+
+        let _guard = lock.write().await;
+
+        let mut s = self.status(&key_str)?;
+        let end = segment.start_seq + segment.rounds - 1;
+
+        // a claim may have landed while the segment was proven
+        ensure!(segment.start_seq == s.proven_seq() + 1, "segment {} does not follow proven seq {}", segment.start_seq, s.proven_seq());
+        ensure!(end <= s.confirmed_seq, "segment {} past confirmed seq", segment.start_seq);
+
+        let mut index: Vec<(u32, u32)> = self.store.get(Table::Segments, &key_str)?.unwrap_or_default();
+        index.push((segment.start_seq, segment.rounds));
+
+        s.proven_seq = end;
+
+        let mut batch = Batch::default();
+        batch.put(Table::Segments, history_key(&key_str, segment.start_seq), segment)?;
+        batch.put(Table::Segments, key_str.clone(), &index)?;
+        batch.put(Table::Status, key_str, &s)?;
+        self.write(batch).await
+    }
+
+    /// Segment receipts per client from `commit_seq` on, whole segments up to `max_rounds` in total.
+    pub async fn load_segments(&self, max_rounds: Option<u32>) -> Result<Vec<(PublicKey, Vec<SegmentReceipt>)>> {
+        let mut keys = self.peers();
+        keys.sort();
+
+        let mut out = Vec::new();
+        let mut budget = max_rounds.unwrap_or(u32::MAX);
+
+        for key in keys {
+            let key_str = hex::encode(&key);
+
+            let s = match self.load_status(key).await? {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let mut index: Vec<(u32, u32)> = self.store.get(Table::Segments, &key_str)?.unwrap_or_default();
+            index.sort();
+
+            let mut next = s.commit_seq + 1;
+            let mut segments = Vec::new();
+
+            for (start_seq, rounds) in index {
+                // the first segment always goes in, otherwise an oversized one would never be claimed
+                if start_seq != next || (rounds > budget && !(out.is_empty() && segments.is_empty())) {
+                    break;
+                }
+
+                let segment: SegmentReceipt = self.store.get(Table::Segments, &history_key(&key_str, start_seq))?
+                    .ok_or_else(|| anyhow!("segment {} not found", start_seq))?;
+
+                budget = budget.saturating_sub(rounds);
+                next += rounds;
+                segments.push(segment);
+            }
+
+            if !segments.is_empty() {
+                out.push((key, segments));
+            }
+        }
+
+        Ok(out)
+    }
+
+    // drops the segments at or below commit_seq, one reaching past it can no longer be aggregated,
+    // so the client is proven again from commit_seq
+    fn prune_segments(&self, key_str: &str, s: &mut PeerStatus, batch: &mut Batch) -> Result<()> {
+        let index: Vec<(u32, u32)> = match self.store.get(Table::Segments, key_str)? {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        let straddled = index.iter().any(|&(start_seq, rounds)| start_seq <= s.commit_seq && start_seq + rounds - 1 > s.commit_seq);
+        let mut kept = Vec::new();
+
+        for (start_seq, rounds) in index {
+            if start_seq <= s.commit_seq || straddled {
+                batch.remove(Table::Segments, history_key(key_str, start_seq));
+            } else {
+                kept.push((start_seq, rounds));
+            }
+        }
+
+        if straddled {
+            s.proven_seq = s.commit_seq;
+        }
+
+        batch.put(Table::Segments, key_str, &kept)
+    }
+
+    pub async fn commit(&self, claims: &[common::Claim], tx_hash: Option<String>) -> Result<()> {
+        self.commit_with(claims, tx_hash, Batch::default()).await
+    }
+
+    pub fn pending_claim(&self) -> Result<Option<PendingClaim>> {
+        self.store.get(Table::Ledger, PENDING_CLAIM_KEY)
+    }
+
+    pub async fn save_pending_claim(&self, pending: &PendingClaim) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.put(Table::Ledger, PENDING_CLAIM_KEY, pending)?;
+        self.write(batch).await
+    }
+
+    pub async fn drop_pending_claim(&self) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.remove(Table::Ledger, PENDING_CLAIM_KEY);
+        self.write(batch).await
+    }
+
+    /// Commits the pending claim and clears it from the ledger in one write,
+    /// claims `reconcile` already committed are skipped. Returns the units settled.
+    pub async fn settle_claim(&self, pending: &PendingClaim, tx_hash: Option<String>) -> Result<u64> {
+        let mut claims = Vec::new();
+
+        for claim in &pending.claims {
+            match self.load_status(claim.pk).await? {
+                Some(s) if s.commit_seq < claim.start_seq => claims.push(claim.clone()),
+                _ => (),
+            }
+        }
+
+        let mut batch = Batch::default();
+        batch.remove(Table::Ledger, PENDING_CLAIM_KEY);
+        self.commit_with(&claims, tx_hash, batch).await?;
+
+        Ok(claims.iter().flat_map(|c| c.units_consumed).sum())
+    }
+
+    async fn commit_with(&self, claims: &[common::Claim], tx_hash: Option<String>, mut batch: Batch) -> Result<()> {
         let keys = self.locks.lock().unwrap().clone();
+        let mut guards = Vec::with_capacity(claims.len());
 
         for claim in claims {
             let key_str = hex::encode(&claim.pk);
 
             let lock = keys.get(&claim.pk).ok_or_else(|| anyhow::anyhow!("no such lock"))?;
-            let _guard = lock.write().await;
+            guards.push(lock.clone().write_owned().await);
 
-            let buf = cacache::read(&self.round_status_dir, &key_str).await?;
-            let mut s: PeerStatus = serde_json::from_slice(&buf)?;
+            let mut s = self.status(&key_str)?;
 
             ensure!(s.commit_seq + 1 == claim.start_seq);
-            ensure!(s.seq >= s.commit_seq + claim.rounds);
+            ensure!(s.confirmed_seq >= s.commit_seq + claim.rounds);
+
+            let mut leaves = Vec::with_capacity(claim.rounds as usize);
+
+            for seq in claim.start_seq..claim.start_seq + claim.rounds {
+                let rd = self.round(&key_str, seq)?;
+                leaves.push(merkle::leaf(rd.req.request.msg, rd.confirm_msg()));
+            }
+
+            // a transcript that doesn't hash to the committed root would only serve proofs that never verify
+            ensure!(
+                merkle::root(&leaves) == claim.transcript_root,
+                "transcript root mismatch, client: {}, seq: {}", key_str, claim.start_seq
+            );
+
+            let transcript = Transcript {
+                start_seq: claim.start_seq,
+                root: claim.transcript_root,
+                leaves,
+            };
+
+            batch.put(Table::Transcripts, history_key(&key_str, claim.start_seq), &transcript)?;
+
+            let mut index: Vec<u32> = self.store.get(Table::Transcripts, &key_str)?.unwrap_or_default();
+            index.push(claim.start_seq);
+            batch.put(Table::Transcripts, key_str.clone(), &index)?;
 
             s.commit_seq += claim.rounds;
-            cacache::write(&self.round_status_dir, &key_str, &serde_json::to_vec(&s)?).await?;
+
+            // the round after the claim is the oldest unclaimed one now
+            s.unclaimed_since = if s.commit_seq < s.confirmed_seq {
+                Some(self.round(&key_str, s.commit_seq + 1)?.requested_at)
+            } else {
+                None
+            };
+
+            for (total, amount) in s.unclaimed.iter_mut().zip(claim.units_consumed) {
+                *total = total.saturating_sub(amount);
+            }
+
+            self.prune_segments(&key_str, &mut s, &mut batch)?;
+
+            batch.put(Table::Status, key_str.clone(), &s)?;
 
             for seq in claim.start_seq..claim.start_seq + claim.rounds {
-                cacache::remove(&self.history_dir, &format!("{}-{}", key_str, seq)).await?;
+                batch.remove(Table::History, history_key(&key_str, seq));
             }
         }
-        Ok(())
+
+        if let Some(first) = claims.first() {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+            let record = ClaimRecord {
+                time,
+                tx_hash,
+                claims: claims.to_vec(),
+            };
+
+            // zero padded so keys sort by time
+            batch.put(Table::Claims, format!("{:020}-{}", time, history_key(&hex::encode(first.pk), first.start_seq)), &record)?;
+        }
+
+        // every claim of one transaction lands together
+        self.write(batch).await
+    }
+
+    pub fn claims(&self) -> Result<Vec<ClaimRecord>> {
+        let mut out = Vec::new();
+
+        for key in self.store.keys(Table::Claims)? {
+            if let Some(record) = self.store.get(Table::Claims, &key)? {
+                out.push(record);
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn transcript_proof(&self, key: PublicKey, seq: u32) -> Result<TranscriptProof> {
+        let key_str = hex::encode(&key);
+
+        let index: Vec<u32> = self.store.get(Table::Transcripts, &key_str)?.ok_or_else(|| anyhow!("no claims for {}", key_str))?;
+
+        let start_seq = index.into_iter()
+            .filter(|&start_seq| start_seq <= seq)
+            .max()
+            .ok_or_else(|| anyhow!("seq {} not claimed", seq))?;
+
+        let transcript: Transcript = self.store.get(Table::Transcripts, &history_key(&key_str, start_seq))?
+            .ok_or_else(|| anyhow!("seq {} not claimed", seq))?;
+
+        let idx = (seq - start_seq) as usize;
+        let proof = MerkleProof::new(&transcript.leaves, idx).ok_or_else(|| anyhow!("seq {} not claimed", seq))?;
+
+        Ok(TranscriptProof {
+            seq,
+            start_seq,
+            rounds: transcript.leaves.len() as u32,
+            transcript_root: transcript.root,
+            leaf: transcript.leaves[idx],
+            proof,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use async_openai::types::CreateEmbeddingRequest;
+    use common::{Confirm, Request, RequestMsg};
+
+    const PK: PublicKey = [7; 32];
+
+    fn cache() -> MetadataCache {
+        MetadataCache::new(Box::new(MemoryStore::default()), Duration::from_secs(60), Duration::from_secs(60))
+    }
+
+    fn open(seq: u32) -> impl FnOnce(&[u64; METER_UNITS]) -> Result<[u64; METER_UNITS]> {
+        move |_| Ok([seq as u64; METER_UNITS])
+    }
+
+    fn request(seq: u32) -> CompletionsReq<ApiRequest> {
+        CompletionsReq {
+            pk: PK,
+            raw_req: ApiRequest::Embedding(CreateEmbeddingRequest::default()),
+            request: Request {
+                msg: RequestMsg { seq, req_hash: [0; 32] },
+                signature: Vec::new(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn void_moves_window() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        cache.req(&request(1), open(1)).await.unwrap();
+        cache.req(&request(2), open(2)).await.unwrap();
+        assert!(cache.req(&request(2), open(2)).await.is_err());
+        assert!(cache.req(&request(SEQ_WINDOW + 1), open(0)).await.is_err());
+
+        cache.void(PK, 2).await.unwrap();
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert_eq!(status.confirmed_seq, 0);
+        assert_eq!(status.held(), [1; METER_UNITS]);
+
+        cache.void(PK, 1).await.unwrap();
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert_eq!(status.confirmed_seq, 2);
+        assert!(status.inflight.is_empty());
+        assert_eq!(status.held(), [0; METER_UNITS]);
+    }
+
+    #[tokio::test]
+    async fn quarantine_stops_proving() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        for seq in 1..=3 {
+            cache.req(&request(seq), open(0)).await.unwrap();
+            cache.void(PK, seq).await.unwrap();
+        }
+
+        cache.quarantine(PK, 2).await.unwrap();
+        let unproven = cache.load_unproven(None).await.unwrap();
+        assert_eq!(unproven[&PK].iter().map(|rd| rd.seq).collect::<Vec<_>>(), [1]);
+
+        // paid past the rejected round by another gateway run
+        cache.update_from_chain(PK, 4).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().unprovable_seq, None);
+    }
+
+    async fn confirmed(cache: &MetadataCache, seq: u32, units: u32) {
+        let resp = serde_json::from_value(serde_json::json!({
+            "object": "list",
+            "model": "m",
+            "data": [],
+            "usage": { "prompt_tokens": 1, "total_tokens": 1 }
+        })).unwrap();
+
+        cache.req(&request(seq), open(0)).await.unwrap();
+        cache.resp(&request(seq), &CompletionsResp { raw_response: ApiResponse::Embedding(resp) }).await.unwrap();
+        cache.confirm(&ConfirmReq {
+            pk: PK,
+            confirm: Confirm {
+                msg: ConfirmMsg { seq, resp_hash: [0; 32], usage: [units; METER_UNITS] },
+                signature: Vec::new(),
+            },
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn quarantined_rounds_are_not_billed() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        for seq in 1..=3 {
+            confirmed(&cache, seq, 1).await;
+        }
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().unclaimed, [3; METER_UNITS]);
+
+        cache.quarantine(PK, 2).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().unclaimed, [1; METER_UNITS]);
+
+        // already out of the bill, nor is a round confirmed past the rejected one
+        cache.quarantine(PK, 3).await.unwrap();
+        confirmed(&cache, 4, 1).await;
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().unclaimed, [1; METER_UNITS]);
+
+        cache.quarantine(PK, 1).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().unclaimed, [0; METER_UNITS]);
+        assert_eq!(cache.oldest_unclaimed().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn legacy_rounds_are_unprovable() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        for seq in 1..=3 {
+            confirmed(&cache, seq, 1).await;
+        }
+
+        // round 2 as an older gateway stored it
+        let key = history_key(&hex::encode(PK), 2);
+        let mut raw: serde_json::Value = serde_json::from_slice(&cache.store.get_raw(Table::History, &key).unwrap().unwrap()).unwrap();
+        raw.as_object_mut().unwrap().remove("version");
+
+        let mut batch = Batch::default();
+        batch.put(Table::History, key, &raw).unwrap();
+        cache.store.write(batch).unwrap();
+
+        // only round 1 is left to claim
+        assert_eq!(cache.load_peers().await.unwrap(), METER_UNITS as u64);
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert_eq!(status.unprovable_seq, Some(2));
+        assert_eq!(status.unclaimed, [1; METER_UNITS]);
+
+        let unproven = cache.load_unproven(None).await.unwrap();
+        assert_eq!(unproven[&PK].iter().map(|rd| rd.seq).collect::<Vec<_>>(), [1]);
+    }
+
+    #[tokio::test]
+    async fn chain_ahead_keeps_open_rounds() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        cache.req(&request(1), open(0)).await.unwrap();
+        cache.req(&request(2), open(1)).await.unwrap();
+        cache.req(&request(3), open(0)).await.unwrap();
+        cache.void(PK, 3).await.unwrap();
+
+        assert_eq!(cache.update_from_chain(PK, 1).await.unwrap(), 0);
+
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert_eq!((status.seq, status.commit_seq, status.confirmed_seq), (3, 1, 1));
+        assert_eq!(status.held(), [1; METER_UNITS]);
+        assert!(cache.load_round(PK, 1).await.is_err());
+        assert!(cache.load_round(PK, 2).await.is_ok());
+
+        cache.void(PK, 2).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().confirmed_seq, 3);
+
+        // behind the confirmed rounds the chain changes nothing here
+        assert_eq!(cache.update_from_chain(PK, 2).await.unwrap(), 0);
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().commit_seq, 1);
+    }
+
+    #[tokio::test]
+    async fn open_rounds_hold_their_reservation() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        cache.req(&request(1), open(3)).await.unwrap();
+
+        let mut seen = None;
+        cache.req(&request(2), |held| {
+            seen = Some(*held);
+            Ok([0; METER_UNITS])
+        }).await.unwrap();
+        assert_eq!(seen, Some([3; METER_UNITS]));
+
+        // a refused reservation leaves no round behind
+        assert!(cache.req(&request(3), |_| Err(anyhow!("insufficient balance"))).await.is_err());
+        assert!(!cache.load_status(PK).await.unwrap().unwrap().inflight.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn refused_clients_stay_unknown() {
+        let cache = cache();
+
+        assert!(cache.req(&request(SEQ_WINDOW + 1), open(0)).await.is_err());
+        assert!(cache.req(&request(1), |_| Err(anyhow!("insufficient balance"))).await.is_err());
+        assert!(cache.load_round(PK, 1).await.is_err());
+        assert!(cache.peers().is_empty());
+
+        cache.req(&request(1), open(0)).await.unwrap();
+        assert_eq!(cache.peers(), [PK]);
+        assert!(cache.load_unproven(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_round_is_claimable() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        let abandon = AbandonReq {
+            req: request(1),
+            confirm: Confirm {
+                msg: ConfirmMsg::void(1),
+                signature: Vec::new(),
+            },
+        };
+        cache.abandon(&abandon).await.unwrap();
+
+        let history = cache.load_unproven(None).await.unwrap();
+        assert_eq!(history[&PK].len(), 1);
+
+        let leaf = merkle::leaf(request(1).request.msg, ConfirmMsg::void(1));
+
+        cache.commit(&[common::Claim {
+            pk: PK,
+            start_seq: 1,
+            rounds: 1,
+            units_consumed: [0; METER_UNITS],
+            transcript_root: merkle::root(&[leaf]),
+        }], None).await.unwrap();
+
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().commit_seq, 1);
+        assert!(cache.load_unproven(None).await.unwrap().is_empty());
+        assert_eq!(cache.transcript_proof(PK, 1).await.unwrap().leaf, leaf);
+    }
+
+    fn abandon(seq: u32) -> AbandonReq<ApiRequest> {
+        AbandonReq {
+            req: request(seq),
+            confirm: Confirm {
+                msg: ConfirmMsg::void(seq),
+                signature: Vec::new(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn abandon_skips_answered_rounds() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        // seqs skipped by the abandoned round are left to the client
+        cache.abandon(&abandon(3)).await.unwrap();
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().holes(), [1, 2]);
+
+        // an answered round is closed by its confirm, not a void
+        let resp = serde_json::from_value(serde_json::json!({
+            "object": "list",
+            "model": "m",
+            "data": [],
+            "usage": { "prompt_tokens": 1, "total_tokens": 1 }
+        })).unwrap();
+
+        cache.req(&request(1), open(0)).await.unwrap();
+        cache.resp(&request(1), &CompletionsResp { raw_response: ApiResponse::Embedding(resp) }).await.unwrap();
+        assert!(cache.abandon(&abandon(1)).await.is_err());
+
+        cache.abandon(&abandon(2)).await.unwrap();
+        let status = cache.load_status(PK).await.unwrap().unwrap();
+        assert!(status.holes().is_empty());
+        assert_eq!(status.confirmed_seq, 0);
+    }
+
+    #[tokio::test]
+    async fn oldest_unclaimed_follows_commits() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+        assert_eq!(cache.oldest_unclaimed().await.unwrap(), None);
+
+        for seq in 1..=2 {
+            cache.req(&request(seq), open(0)).await.unwrap();
+            cache.void(PK, seq).await.unwrap();
+        }
+
+        let requested_at = cache.load_round(PK, 1).await.unwrap().requested_at;
+        assert_eq!(cache.oldest_unclaimed().await.unwrap(), Some(requested_at));
+
+        for seq in 1..=2 {
+            let leaf = merkle::leaf(request(seq).request.msg, ConfirmMsg::void(seq));
+
+            cache.commit(&[common::Claim {
+                pk: PK,
+                start_seq: seq,
+                rounds: 1,
+                units_consumed: [0; METER_UNITS],
+                transcript_root: merkle::root(&[leaf]),
+            }], None).await.unwrap();
+        }
+
+        assert_eq!(cache.oldest_unclaimed().await.unwrap(), None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn rejects_mismatched_transcript_root() {
+        let cache = cache();
+        cache.update_from_chain(PK, 0).await.unwrap();
+
+        cache.req(&request(1), open(0)).await.unwrap();
+        cache.void(PK, 1).await.unwrap();
+
+        let claim = common::Claim {
+            pk: PK,
+            start_seq: 1,
+            rounds: 1,
+            units_consumed: [0; METER_UNITS],
+            transcript_root: [0xff; 32],
+        };
+
+        assert!(cache.commit(&[claim], None).await.is_err());
+        assert_eq!(cache.load_status(PK).await.unwrap().unwrap().commit_seq, 0);
+        assert!(cache.transcript_proof(PK, 1).await.is_err());
+    }
+}
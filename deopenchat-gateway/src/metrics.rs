@@ -0,0 +1,127 @@
+use anyhow::Result;
+use common::api::ApiRequest;
+use common::{MeterUnit, PublicKey, METER_UNITS};
+use prometheus::{Counter, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use crate::metadata::{PeerStatus, RoundState};
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub confirms: IntCounterVec,
+    pub signature_failures: IntCounterVec,
+    pub backend_latency: HistogramVec,
+    pub proving_duration: Histogram,
+    pub claims: IntCounterVec,
+    pub claim_gas: Histogram,
+    pub payout_wei: Counter,
+    rounds: IntGaugeVec,
+    unclaimed_units: IntGaugeVec,
+    unprovable_clients: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("deopenchat".to_string()), None)?;
+
+        let requests = IntCounterVec::new(Opts::new("requests_total", "metered requests by kind and result"), &["kind", "result"])?;
+        let confirms = IntCounterVec::new(Opts::new("confirms_total", "round confirms by result"), &["result"])?;
+        let signature_failures = IntCounterVec::new(Opts::new("signature_failures_total", "rejected client signatures"), &["msg"])?;
+        let backend_latency = HistogramVec::new(HistogramOpts::new("backend_latency_seconds", "backend response time, until the first chunk for streams"), &["kind"])?;
+        let proving_duration = Histogram::with_opts(
+            HistogramOpts::new("proving_duration_seconds", "time to prove a claim")
+                .buckets(prometheus::exponential_buckets(1.0, 2.0, 12)?)
+        )?;
+        let claims = IntCounterVec::new(Opts::new("claims_total", "claim transactions by result"), &["result"])?;
+        let claim_gas = Histogram::with_opts(
+            HistogramOpts::new("claim_gas_used", "gas used by claim transactions")
+                .buckets(prometheus::exponential_buckets(100_000.0, 2.0, 10)?)
+        )?;
+        let payout_wei = Counter::new("payout_wei_total", "wei paid out by claims")?;
+        let rounds = IntGaugeVec::new(Opts::new("rounds", "rounds not yet claimed by state"), &["state"])?;
+        let unclaimed_units = IntGaugeVec::new(Opts::new("unclaimed_units", "confirmed units not yet claimed, over all clients"), &["unit"])?;
+        let unprovable_clients = IntGauge::new("unprovable_clients", "clients with rounds the circuit rejects")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(confirms.clone()))?;
+        registry.register(Box::new(signature_failures.clone()))?;
+        registry.register(Box::new(backend_latency.clone()))?;
+        registry.register(Box::new(proving_duration.clone()))?;
+        registry.register(Box::new(claims.clone()))?;
+        registry.register(Box::new(claim_gas.clone()))?;
+        registry.register(Box::new(payout_wei.clone()))?;
+        registry.register(Box::new(rounds.clone()))?;
+        registry.register(Box::new(unclaimed_units.clone()))?;
+        registry.register(Box::new(unprovable_clients.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            confirms,
+            signature_failures,
+            backend_latency,
+            proving_duration,
+            claims,
+            claim_gas,
+            payout_wei,
+            rounds,
+            unclaimed_units,
+            unprovable_clients,
+        })
+    }
+
+    /// Text exposition of every metric, the round gauges are recomputed from `statuses`.
+    pub fn render(&self, statuses: &[(PublicKey, PeerStatus)]) -> Result<Vec<u8>> {
+        let mut rounds = [("unclaimed", 0i64), ("requested", 0), ("waiting_confirm", 0), ("completed", 0)];
+        let mut unclaimed = [0u64; METER_UNITS];
+        let mut unprovable = 0;
+
+        for (_, status) in statuses {
+            unprovable += status.unprovable_seq.is_some() as i64;
+
+            rounds[0].1 += (status.confirmed_seq - status.commit_seq) as i64;
+
+            for round in status.inflight.values() {
+                let state = match round.state {
+                    RoundState::Requested => 1,
+                    RoundState::WaitingConfirm => 2,
+                    RoundState::Completed => 3,
+                };
+                rounds[state].1 += 1;
+            }
+
+            for (total, amount) in unclaimed.iter_mut().zip(status.unclaimed) {
+                *total += amount;
+            }
+        }
+
+        // every label is overwritten, a concurrent scrape never sees the gauges half rebuilt
+        for (state, count) in rounds {
+            self.rounds.with_label_values(&[state]).set(count);
+        }
+
+        for (unit, amount) in MeterUnit::ALL.iter().zip(unclaimed) {
+            self.unclaimed_units.with_label_values(&[unit.name()]).set(amount as i64);
+        }
+
+        self.unprovable_clients.set(unprovable);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub fn kind_label(req: &ApiRequest) -> &'static str {
+    match req {
+        ApiRequest::Completion(_) => "completion",
+        ApiRequest::Chat(_) => "chat",
+        ApiRequest::Embedding(_) => "embedding",
+    }
+}
+
+pub fn result_label<T, E>(res: &std::result::Result<T, E>) -> &'static str {
+    match res {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use anyhow::{ensure, Result};
+use common::Input;
+use deopenchat_prover::remote::{Job, RemoteProver};
+use deopenchat_prover::{Prover, Receipt};
+
+/// Where the claim loop has its proofs made.
+pub enum ProverTarget {
+    // in this process, on a blocking thread
+    Local(Arc<dyn Prover>),
+    // a `deopenchat-prover daemon`
+    Remote(RemoteProver),
+}
+
+impl ProverTarget {
+    async fn run(&self, job: Job) -> Result<Receipt> {
+        match self {
+            ProverTarget::Local(prover) => {
+                let prover = prover.clone();
+                tokio::task::spawn_blocking(move || deopenchat_prover::run(prover.as_ref(), job)).await?
+            }
+            ProverTarget::Remote(remote) => {
+                let image_id = match job {
+                    Job::Segment(_) => deopenchat_prover::segment_image_id(),
+                    Job::Aggregate(_) => deopenchat_prover::image_id(),
+                };
+
+                // the daemon is not trusted, its receipt must hold for the guest we asked for
+                let receipt = remote.prove(&job).await?;
+                receipt.verify(image_id)?;
+                Ok(receipt)
+            }
+        }
+    }
+
+    pub async fn prove_segment(&self, input: Input) -> Result<Receipt> {
+        let journal = deopenchat_prover::segment_journal(&input)?;
+        let receipt = self.run(Job::Segment(input)).await?;
+
+        // a receipt of other rounds would be saved as these
+        ensure!(receipt.journal.bytes == journal, "segment journal does not match the submitted rounds");
+        Ok(receipt)
+    }
+
+    pub async fn aggregate(&self, receipts: Vec<Receipt>) -> Result<Receipt> {
+        self.run(Job::Aggregate(receipts)).await
+    }
+}
@@ -0,0 +1,130 @@
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, ensure};
+use common::PublicKey;
+
+/// UTC hours `[start, end)` in which claims may be sent, wraps around midnight when `start > end`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClaimWindow {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl ClaimWindow {
+    pub fn contains(&self, unix_secs: u64) -> bool {
+        let hour = ((unix_secs / 3600) % 24) as u8;
+
+        if self.start_hour <= self.end_hour {
+            hour >= self.start_hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl FromStr for ClaimWindow {
+    type Err = anyhow::Error;
+
+    // "22-6"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| anyhow!("expected <start hour>-<end hour>"))?;
+        let start_hour: u8 = start.trim().parse()?;
+        let end_hour: u8 = end.trim().parse()?;
+
+        ensure!(start_hour < 24 && end_hour <= 24, "hours must be within 0..=24");
+        ensure!(start_hour != end_hour, "empty claim window");
+
+        Ok(ClaimWindow { start_hour, end_hour })
+    }
+}
+
+/// Unclaimed work the policy decides on.
+pub struct Backlog {
+    // wei the unclaimed units of each client pay at the current prices
+    pub clients: Vec<(PublicKey, u128)>,
+    // unix seconds of the oldest unclaimed round
+    pub oldest: Option<u64>,
+}
+
+pub struct ClaimPolicy {
+    // levels are wei of unclaimed payout, units of different kinds only add up by their price
+    pub high_water_level: u128,
+    pub client_high_water_level: Option<u128>,
+    pub max_age: Option<Duration>,
+    pub window: Option<ClaimWindow>,
+    // claim only if the payout covers the estimated gas cost this many times
+    pub min_profit_ratio: Option<f64>,
+    pub base_gas: u64,
+    pub gas_per_client: u64,
+    // upper bound on the rounds proven in one claim
+    pub max_rounds: Option<u32>,
+}
+
+impl ClaimPolicy {
+    /// Whether the backlog is due for a claim, the profitability check comes on top.
+    pub fn due(&self, backlog: &Backlog, now: u64) -> bool {
+        if self.window.is_some_and(|w| !w.contains(now)) {
+            return false;
+        }
+
+        if backlog.clients.iter().map(|(_, payout)| payout).sum::<u128>() >= self.high_water_level {
+            return true;
+        }
+
+        let client_due = self.client_high_water_level.is_some_and(|level| {
+            backlog.clients.iter().any(|&(_, payout)| payout >= level)
+        });
+
+        let age_due = match (self.max_age, backlog.oldest) {
+            (Some(max_age), Some(oldest)) => now.saturating_sub(oldest) >= max_age.as_secs(),
+            _ => false,
+        };
+
+        client_due || age_due
+    }
+
+    pub fn estimated_gas(&self, clients: usize) -> u64 {
+        self.base_gas + self.gas_per_client * clients as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_wraps_midnight() {
+        let window: ClaimWindow = "22-6".parse().unwrap();
+
+        assert!(window.contains(23 * 3600));
+        assert!(window.contains(24 * 3600 + 5 * 3600));
+        assert!(!window.contains(6 * 3600));
+        assert!(!window.contains(12 * 3600));
+
+        assert!("6-6".parse::<ClaimWindow>().is_err());
+        assert!("25-3".parse::<ClaimWindow>().is_err());
+    }
+
+    #[test]
+    fn levels_compare_payout() {
+        let policy = ClaimPolicy {
+            high_water_level: 100,
+            client_high_water_level: Some(60),
+            max_age: None,
+            window: None,
+            min_profit_ratio: None,
+            base_gas: 0,
+            gas_per_client: 0,
+            max_rounds: None,
+        };
+
+        let backlog = |payouts: &[u128]| Backlog {
+            clients: payouts.iter().enumerate().map(|(i, &payout)| ([i as u8; 32], payout)).collect(),
+            oldest: None,
+        };
+
+        assert!(!policy.due(&backlog(&[50, 40]), 0));
+        assert!(policy.due(&backlog(&[50, 50]), 0));
+        assert!(policy.due(&backlog(&[60]), 0));
+    }
+}
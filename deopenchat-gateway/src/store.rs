@@ -0,0 +1,239 @@
+#[cfg(test)]
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+#[cfg(test)]
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use log::info;
+use redb::{Database, ReadableTable, TableDefinition, TableError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::legacy;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Table {
+    Status,
+    History,
+    Transcripts,
+    Claims,
+    Ledger,
+    Segments,
+}
+
+impl Table {
+    fn name(&self) -> &'static str {
+        match self {
+            Table::Status => "status",
+            Table::History => "history",
+            Table::Transcripts => "transcripts",
+            Table::Claims => "claims",
+            Table::Ledger => "ledger",
+            Table::Segments => "segments",
+        }
+    }
+
+    fn definition(&self) -> TableDefinition<'static, &'static str, &'static [u8]> {
+        TableDefinition::new(self.name())
+    }
+}
+
+enum Op {
+    Put(Table, String, Vec<u8>),
+    Remove(Table, String),
+}
+
+/// Writes applied by `MetadataStore::write` all together or not at all.
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn put<V: Serialize>(&mut self, table: Table, key: impl Into<String>, value: &V) -> Result<()> {
+        self.ops.push(Op::Put(table, key.into(), serde_json::to_vec(value)?));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, table: Table, key: impl Into<String>) {
+        self.ops.push(Op::Remove(table, key.into()));
+    }
+}
+
+/// Namespaced key-value store behind `MetadataCache`.
+pub trait MetadataStore: Send + Sync {
+    fn get_raw(&self, table: Table, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn keys(&self, table: Table) -> Result<Vec<String>>;
+
+    // applies the whole batch in one transaction
+    fn write(&self, batch: Batch) -> Result<()>;
+}
+
+impl dyn MetadataStore {
+    pub fn get<V: DeserializeOwned>(&self, table: Table, key: &str) -> Result<Option<V>> {
+        match self.get_raw(table, key)? {
+            None => Ok(None),
+            Some(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        }
+    }
+}
+
+// cacache directories of gateways before `RedbStore`, keyed like the tables
+const LEGACY_DIRS: [(&str, Table); 3] = [
+    ("status", Table::Status),
+    ("history", Table::History),
+    ("transcripts", Table::Transcripts),
+];
+
+pub struct RedbStore {
+    db: Database,
+}
+
+impl RedbStore {
+    pub fn open(cache_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(cache_dir)?;
+        let path = cache_dir.join("metadata.redb");
+
+        if !path.exists() {
+            Self::import_legacy(cache_dir, &path)?;
+        }
+
+        let db = Database::create(path)?;
+        Ok(RedbStore { db })
+    }
+
+    /// Copies the cacache entries of an older gateway into a new database at `path`, converted by `legacy::convert`.
+    ///
+    /// The import is built aside and renamed in place, an interrupted one starts over.
+    fn import_legacy(cache_dir: &Path, path: &Path) -> Result<()> {
+        let dirs = LEGACY_DIRS.iter()
+            .map(|&(dir, table)| (cache_dir.join(dir), table))
+            .filter(|(dir, _)| dir.is_dir())
+            .collect::<Vec<_>>();
+
+        if dirs.is_empty() {
+            return Ok(());
+        }
+
+        let import_path = path.with_extension("import");
+
+        if import_path.exists() {
+            std::fs::remove_file(&import_path)?;
+        }
+
+        let store = RedbStore { db: Database::create(&import_path)? };
+        let mut batch = Batch::default();
+
+        for (dir, table) in dirs {
+            for entry in cacache::list_sync(&dir) {
+                let entry = entry?;
+
+                let value = match cacache::read_sync(&dir, &entry.key) {
+                    Ok(value) => value,
+                    // removed after it was listed
+                    Err(cacache::Error::EntryNotFound(_, _)) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+
+                let value = legacy::convert(table, &value)
+                    .with_context(|| format!("can't import legacy {} entry {}", table.name(), entry.key))?;
+
+                batch.ops.push(Op::Put(table, entry.key, value));
+            }
+        }
+
+        let entries = batch.ops.len();
+        store.write(batch)?;
+        drop(store);
+
+        std::fs::rename(&import_path, path)?;
+        info!("imported {} legacy cache entries into {}", entries, path.display());
+        Ok(())
+    }
+}
+
+impl MetadataStore for RedbStore {
+    fn get_raw(&self, table: Table, key: &str) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+
+        let t = match txn.open_table(table.definition()) {
+            Ok(t) => t,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let value = t.get(key)?.map(|v| v.value().to_vec());
+        Ok(value)
+    }
+
+    fn keys(&self, table: Table) -> Result<Vec<String>> {
+        let txn = self.db.begin_read()?;
+
+        let t = match txn.open_table(table.definition()) {
+            Ok(t) => t,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+
+        for entry in t.iter()? {
+            let (k, _) = entry?;
+            keys.push(k.value().to_string());
+        }
+        Ok(keys)
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        let txn = self.db.begin_write()?;
+
+        for op in batch.ops {
+            match op {
+                Op::Put(table, key, value) => {
+                    txn.open_table(table.definition())?.insert(key.as_str(), value.as_slice())?;
+                }
+                Op::Remove(table, key) => {
+                    txn.open_table(table.definition())?.remove(key.as_str())?;
+                }
+            }
+        }
+
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<HashMap<Table, BTreeMap<String, Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl MetadataStore for MemoryStore {
+    fn get_raw(&self, table: Table, key: &str) -> Result<Option<Vec<u8>>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.get(&table).and_then(|t| t.get(key)).cloned())
+    }
+
+    fn keys(&self, table: Table) -> Result<Vec<String>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.get(&table).map(|t| t.keys().cloned().collect()).unwrap_or_default())
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+
+        for op in batch.ops {
+            match op {
+                Op::Put(table, key, value) => {
+                    tables.entry(table).or_default().insert(key, value);
+                }
+                Op::Remove(table, key) => {
+                    tables.entry(table).or_default().remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
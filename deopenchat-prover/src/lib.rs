@@ -1,36 +1,319 @@
 use anyhow::Result;
-use deopenchat_zkcircuit::{GUEST_CODE_FOR_ZK_PROOF_ELF, GUEST_CODE_FOR_ZK_PROOF_ID};
-use common::Input;
-use risc0_zkvm::{default_prover, ExecutorEnv, ProveInfo, ProverOpts};
+use clap::ValueEnum;
+use deopenchat_zkcircuit::{GUEST_CODE_FOR_AGGREGATE_ELF, GUEST_CODE_FOR_AGGREGATE_ID, GUEST_CODE_FOR_ZK_PROOF_ELF, GUEST_CODE_FOR_ZK_PROOF_ID};
+use common::{circuit, AggregateInput, Input, Segment};
+use log::info;
+use risc0_zkvm::{default_executor, default_prover, ExecutorEnv, FakeReceipt, InnerReceipt, ProverOpts, ReceiptClaim};
 use risc0_zkvm::sha::Digest;
+use crate::remote::Job;
 
-pub fn prove(input: Input) -> Result<ProveInfo> {
-    let env = ExecutorEnv::builder()
+pub use risc0_zkvm::Receipt;
+
+pub mod remote;
+
+/// Proves the rounds guest into segments and the aggregation guest into claims.
+pub trait Prover: Send + Sync {
+    fn prove_segment(&self, input: Input) -> Result<Receipt>;
+
+    // segments of a client must be passed in seq order
+    fn aggregate(&self, receipts: Vec<Receipt>) -> Result<Receipt>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProverKind {
+    // claims the contract verifies
+    Groth16,
+    // real proofs without the groth16 wrap, not verifiable on chain
+    Succinct,
+    // runs the guests for cycle counts and journals, proves nothing
+    Executor,
+    // computes the journals natively, for tests against a mock verifier
+    Dev,
+}
+
+impl ProverKind {
+    pub fn build(self) -> Box<dyn Prover> {
+        match self {
+            ProverKind::Groth16 => Box::new(ZkvmProver { aggregate_opts: ProverOpts::groth16() }),
+            ProverKind::Succinct => Box::new(ZkvmProver { aggregate_opts: ProverOpts::succinct() }),
+            ProverKind::Executor => Box::new(ExecutorProver),
+            ProverKind::Dev => Box::new(DevProver),
+        }
+    }
+}
+
+fn segment_journals(receipts: &[Receipt]) -> Result<Vec<Vec<Segment>>> {
+    receipts.iter()
+        .map(|receipt| Ok(receipt.journal.decode::<Vec<Segment>>()?))
+        .collect()
+}
+
+// rejects what the guest would panic on before any cycles are spent
+fn segment_env(input: &Input) -> Result<ExecutorEnv<'static>> {
+    circuit::prove_rounds(input)?;
+
+    ExecutorEnv::builder()
+        .write(input)?
+        .build()
+}
+
+fn aggregate_env(receipts: Vec<Receipt>) -> Result<ExecutorEnv<'static>> {
+    let input = AggregateInput {
+        segment_image_id: GUEST_CODE_FOR_ZK_PROOF_ID,
+        segments: segment_journals(&receipts)?,
+    };
+
+    circuit::merge_segments(input.segment_image_id, &input.segments)?;
+
+    let mut builder = ExecutorEnv::builder();
+
+    for receipt in receipts {
+        builder.add_assumption(receipt);
+    }
+
+    builder
         .write(&input)?
-        .build()?;
+        .build()
+}
+
+// receipt without a seal, only a mock verifier accepts it
+fn fake_receipt(image_id: impl Into<Digest>, journal: Vec<u8>) -> Receipt {
+    let claim = ReceiptClaim::ok(image_id, journal.clone());
+    Receipt::new(InnerReceipt::Fake(FakeReceipt::new(claim)), journal)
+}
+
+pub struct ZkvmProver {
+    aggregate_opts: ProverOpts,
+}
+
+impl Prover for ZkvmProver {
+    fn prove_segment(&self, input: Input) -> Result<Receipt> {
+        let env = segment_env(&input)?;
+
+        // segments are only ever verified by the aggregation guest
+        let prove_info = default_prover()
+            .prove_with_opts(env, GUEST_CODE_FOR_ZK_PROOF_ELF, &ProverOpts::succinct())?;
+
+        prove_info.receipt.verify(GUEST_CODE_FOR_ZK_PROOF_ID)?;
+        Ok(prove_info.receipt)
+    }
+
+    fn aggregate(&self, receipts: Vec<Receipt>) -> Result<Receipt> {
+        let env = aggregate_env(receipts)?;
+
+        let prove_info = default_prover()
+            .prove_with_opts(env, GUEST_CODE_FOR_AGGREGATE_ELF, &self.aggregate_opts)?;
+
+        prove_info.receipt.verify(GUEST_CODE_FOR_AGGREGATE_ID)?;
+        Ok(prove_info.receipt)
+    }
+}
+
+pub struct ExecutorProver;
+
+impl Prover for ExecutorProver {
+    fn prove_segment(&self, input: Input) -> Result<Receipt> {
+        let env = segment_env(&input)?;
+
+        let session = default_executor().execute(env, GUEST_CODE_FOR_ZK_PROOF_ELF)?;
+        info!("segment executed, cycles: {}", session.cycles());
+
+        Ok(fake_receipt(GUEST_CODE_FOR_ZK_PROOF_ID, session.journal.bytes))
+    }
+
+    fn aggregate(&self, receipts: Vec<Receipt>) -> Result<Receipt> {
+        let env = aggregate_env(receipts)?;
+
+        let session = default_executor().execute(env, GUEST_CODE_FOR_AGGREGATE_ELF)?;
+        info!("aggregation executed, cycles: {}", session.cycles());
+
+        Ok(fake_receipt(GUEST_CODE_FOR_AGGREGATE_ID, session.journal.bytes))
+    }
+}
+
+pub struct DevProver;
 
-    let prover = default_prover();
-    let prover_opts = ProverOpts::groth16();
+impl Prover for DevProver {
+    fn prove_segment(&self, input: Input) -> Result<Receipt> {
+        Ok(fake_receipt(GUEST_CODE_FOR_ZK_PROOF_ID, segment_journal(&input)?))
+    }
+
+    fn aggregate(&self, receipts: Vec<Receipt>) -> Result<Receipt> {
+        let journals = segment_journals(&receipts)?;
+        let journal = circuit::merge_segments(GUEST_CODE_FOR_ZK_PROOF_ID, &journals)?;
+
+        Ok(fake_receipt(GUEST_CODE_FOR_AGGREGATE_ID, journal))
+    }
+}
+
+/// Proves a job of the daemon API in this process.
+pub fn run(prover: &dyn Prover, job: Job) -> Result<Receipt> {
+    match job {
+        Job::Segment(input) => prover.prove_segment(input),
+        Job::Aggregate(receipts) => prover.aggregate(receipts),
+    }
+}
+
+/// Journal the rounds guest commits for `input`, computed natively.
+pub fn segment_journal(input: &Input) -> Result<Vec<u8>> {
+    let segments = circuit::prove_rounds(input)?;
 
-    let prove_info = prover
-        .prove_with_opts(env, GUEST_CODE_FOR_ZK_PROOF_ELF, &prover_opts)?;
+    // what env::commit writes, the words of the zkvm serde encoding
+    let journal = risc0_zkvm::serde::to_vec(&segments)?
+        .into_iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
 
-    prove_info.receipt.verify(GUEST_CODE_FOR_ZK_PROOF_ID)?;
-    Ok(prove_info)
+    Ok(journal)
 }
 
+/// Cycles the guest takes on `input`, measured by the executor without proving.
+pub fn execute(input: &Input) -> Result<u64> {
+    let env = segment_env(input)?;
+
+    let session = default_executor()
+        .execute(env, GUEST_CODE_FOR_ZK_PROOF_ELF)?;
+
+    Ok(session.cycles())
+}
+
+/// Image id the contract verifies claims against.
 pub fn image_id() -> Digest {
+    Digest::from(GUEST_CODE_FOR_AGGREGATE_ID)
+}
+
+pub fn segment_image_id() -> Digest {
     Digest::from(GUEST_CODE_FOR_ZK_PROOF_ID)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+    use common::circuit::CircuitError;
+    use common::journal::ClaimJournal;
+    use common::{Confirm, ConfirmMsg, Domain, Request, RequestContent, RequestKind, RequestMsg, Round};
+    use ed25519_dalek::{Signer, SigningKey};
     use risc0_zkvm::sha::Digest;
-    use deopenchat_zkcircuit::GUEST_CODE_FOR_ZK_PROOF_ID;
+    use deopenchat_zkcircuit::{GUEST_CODE_FOR_AGGREGATE_ID, GUEST_CODE_FOR_ZK_PROOF_ELF, GUEST_CODE_FOR_ZK_PROOF_ID};
+    use super::*;
 
     #[test]
     fn print_image_id() {
-        let image_id = Digest::from(GUEST_CODE_FOR_ZK_PROOF_ID);
+        let image_id = Digest::from(GUEST_CODE_FOR_AGGREGATE_ID);
         println!("image id: {}", image_id);
+
+        let segment_image_id = Digest::from(GUEST_CODE_FOR_ZK_PROOF_ID);
+        println!("segment image id: {}", segment_image_id);
     }
-}
\ No newline at end of file
+
+    const DOMAIN: Domain = Domain {
+        version: common::PROTOCOL_VERSION,
+        chain_id: 31337,
+        contract: [1; 20],
+        provider: [2; 20],
+    };
+
+    fn input(sk: &SigningKey, seqs: std::ops::RangeInclusive<u32>) -> Input {
+        let rounds = seqs
+            .map(|seq| {
+                let content = RequestContent {
+                    kind: RequestKind::Chat,
+                    model: "m".to_string(),
+                    input: format!("\"prompt {}\"", seq),
+                    max_tokens: None,
+                };
+
+                let msg = RequestMsg { seq, req_hash: content.digest() };
+                let confirm_msg = ConfirmMsg { seq, resp_hash: [1; 32], usage: [10, 20, 0, 0, 0] };
+
+                Round {
+                    request: Request {
+                        msg,
+                        signature: sk.sign(&DOMAIN.request_payload(msg)).to_vec(),
+                    },
+                    confirm: Some(Confirm {
+                        msg: confirm_msg,
+                        signature: sk.sign(&DOMAIN.confirm_payload(confirm_msg)).to_vec(),
+                    }),
+                }
+            })
+            .collect();
+
+        Input {
+            domain: DOMAIN,
+            rounds: BTreeMap::from([(sk.verifying_key().to_bytes(), rounds)])
+        }
+    }
+
+    #[test]
+    fn dev_prover_claims_segments() {
+        let sk = SigningKey::from_bytes(&[7; 32]);
+        let prover = ProverKind::Dev.build();
+
+        let first = prover.prove_segment(input(&sk, 1..=3)).unwrap();
+        let second = prover.prove_segment(input(&sk, 4..=5)).unwrap();
+        let receipt = prover.aggregate(vec![first, second]).unwrap();
+
+        let journal = ClaimJournal::try_from(receipt.journal.bytes.as_slice()).unwrap();
+        assert_eq!(journal.domain, DOMAIN);
+        assert_eq!(journal.claims.len(), 1);
+        assert_eq!(journal.claims[0].start_seq, 1);
+        assert_eq!(journal.claims[0].rounds, 5);
+        assert_eq!(journal.claims[0].units_consumed[0], 50);
+        assert_eq!(&journal.segment_image_id, segment_image_id().as_bytes());
+    }
+
+    fn malformed(sk: &SigningKey, f: impl FnOnce(&mut Vec<Round>)) -> Input {
+        let mut input = input(sk, 1..=3);
+        f(input.rounds.values_mut().next().unwrap());
+        input
+    }
+
+    // runs the guest without the host checks
+    fn execute_guest(input: &Input) -> Result<Vec<u8>> {
+        let env = ExecutorEnv::builder()
+            .write(input)?
+            .build()?;
+
+        Ok(default_executor().execute(env, GUEST_CODE_FOR_ZK_PROOF_ELF)?.journal.bytes)
+    }
+
+    #[test]
+    fn executor_matches_dev_prover() {
+        let sk = SigningKey::from_bytes(&[7; 32]);
+
+        let executed = ProverKind::Executor.build().prove_segment(input(&sk, 1..=3)).unwrap();
+        let dev = ProverKind::Dev.build().prove_segment(input(&sk, 1..=3)).unwrap();
+        assert_eq!(executed.journal.bytes, dev.journal.bytes);
+    }
+
+    #[test]
+    fn rejects_malformed_rounds() {
+        let sk = SigningKey::from_bytes(&[7; 32]);
+        let pk = sk.verifying_key().to_bytes();
+
+        let cases = [
+            (malformed(&sk, |rounds| rounds.clear()), CircuitError::EmptyRounds(pk)),
+            (malformed(&sk, |rounds| { rounds.remove(1); }), CircuitError::SeqMismatch { pk, expected: 2, got: 3 }),
+            (malformed(&sk, |rounds| rounds[0].request.signature.truncate(63)), CircuitError::SignatureLength { pk, seq: 1, len: 63 }),
+            (
+                malformed(&sk, |rounds| rounds[1].confirm.as_mut().unwrap().signature[0] ^= 1),
+                CircuitError::InvalidSignature { pk, seq: 2 }
+            ),
+            (malformed(&sk, |rounds| rounds[2].request.msg.req_hash[0] ^= 1), CircuitError::InvalidSignature { pk, seq: 3 }),
+        ];
+
+        // signed for another provider
+        let mut replayed = input(&sk, 1..=3);
+        replayed.domain.provider = [3; 20];
+
+        let cases = cases.into_iter().chain([(replayed, CircuitError::InvalidSignature { pk, seq: 1 })]);
+
+        for (input, expected) in cases {
+            assert!(execute_guest(&input).is_err());
+
+            let err = ProverKind::Executor.build().prove_segment(input).unwrap_err();
+            assert_eq!(err.downcast_ref::<CircuitError>(), Some(&expected));
+        }
+    }
+}
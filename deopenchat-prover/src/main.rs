@@ -0,0 +1,305 @@
+use anyhow::{anyhow, ensure, Result};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::{Parser, Subcommand};
+use deopenchat_prover::remote::{Job, JobInfo, JobStatus};
+use deopenchat_prover::{Prover, ProverKind, Receipt};
+use log::{error, info, LevelFilter};
+use log4rs::append::console::ConsoleAppender;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::collections::HashMap;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+// aggregate jobs carry every segment receipt of a claim
+const MAX_JOB_SIZE: usize = 1024 * 1024 * 1024;
+// queued jobs are held in memory, a full queue turns submits away
+const MAX_QUEUED_JOBS: usize = 8;
+
+fn logger_init() -> anyhow::Result<()> {
+    let log_level = LevelFilter::from_str(
+        std::env::var("DEOPENCHAT_PROVER_LOG").as_deref().unwrap_or("INFO"),
+    )?;
+
+    let pattern = if log_level >= LevelFilter::Debug {
+        "[{d(%Y-%m-%d %H:%M:%S)}] {h({l})} {f}:{L} - {m}{n}"
+    } else {
+        "[{d(%Y-%m-%d %H:%M:%S)}] {h({l})} {t} - {m}{n}"
+    };
+
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(pattern)))
+        .build();
+
+    let config = log4rs::Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .build(
+            Root::builder()
+                .appender("stdout")
+                .build(log_level),
+        )?;
+
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
+struct JobEntry {
+    status: JobStatus,
+    receipt: Option<Receipt>,
+    // finished jobs are dropped once this is older than the job ttl
+    finished_at: Option<Instant>,
+}
+
+struct Context {
+    jobs: Mutex<HashMap<u64, JobEntry>>,
+    next_id: AtomicU64,
+    queue: mpsc::Sender<(u64, Job)>,
+    job_ttl: Duration,
+    prover: Box<dyn Prover>,
+}
+
+impl Context {
+    fn finish(&self, id: u64, res: Result<Receipt>) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        if let Some(entry) = jobs.get_mut(&id) {
+            match res {
+                Ok(receipt) => {
+                    entry.status = JobStatus::Done;
+                    entry.receipt = Some(receipt);
+                }
+                Err(e) => {
+                    error!("job {} failed: {:?}", id, e);
+                    entry.status = JobStatus::Failed(e.to_string());
+                }
+            }
+            entry.finished_at = Some(Instant::now());
+        }
+    }
+
+    fn prune(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, entry| match entry.finished_at {
+            Some(t) => t.elapsed() < self.job_ttl,
+            None => true,
+        });
+    }
+}
+
+// one job at a time, proving takes the whole machine
+fn worker(ctx: Arc<Context>, mut rx: mpsc::Receiver<(u64, Job)>) {
+    while let Some((id, job)) = rx.blocking_recv() {
+        if let Some(entry) = ctx.jobs.lock().unwrap().get_mut(&id) {
+            entry.status = JobStatus::Proving;
+        }
+
+        info!("proving job {}", id);
+        let t = Instant::now();
+        let res = deopenchat_prover::run(ctx.prover.as_ref(), job);
+        info!("job {} finished in {:?}", id, t.elapsed());
+
+        ctx.finish(id, res);
+    }
+}
+
+async fn auth(State(token): State<Arc<String>>, req: Request, next: Next) -> Response {
+    let authorized = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())));
+
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(req).await
+}
+
+fn json_response<V: serde::Serialize>(value: &V) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(buf) => Response::new(Body::from(buf)),
+        Err(e) => {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+async fn submit(State(ctx): State<Arc<Context>>, Json(job): Json<Job>) -> Response {
+    ctx.prune();
+
+    let permit = match ctx.queue.try_reserve() {
+        Ok(permit) => permit,
+        Err(TrySendError::Full(_)) => {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("job queue is full"))
+                .unwrap();
+        }
+        Err(TrySendError::Closed(_)) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("prover worker stopped"))
+                .unwrap();
+        }
+    };
+
+    let id = ctx.next_id.fetch_add(1, Ordering::Relaxed);
+
+    ctx.jobs.lock().unwrap().insert(id, JobEntry {
+        status: JobStatus::Queued,
+        receipt: None,
+        finished_at: None,
+    });
+
+    permit.send((id, job));
+
+    info!("job {} queued", id);
+    json_response(&JobInfo { id, status: JobStatus::Queued })
+}
+
+async fn status(State(ctx): State<Arc<Context>>, Path(id): Path<u64>) -> Response {
+    let status = ctx.jobs.lock().unwrap().get(&id).map(|entry| entry.status.clone());
+
+    match status {
+        Some(status) => json_response(&JobInfo { id, status }),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn receipt(State(ctx): State<Arc<Context>>, Path(id): Path<u64>) -> Response {
+    let jobs = ctx.jobs.lock().unwrap();
+
+    match jobs.get(&id).and_then(|entry| entry.receipt.as_ref()) {
+        Some(receipt) => json_response(receipt),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn daemon(bind_addr: SocketAddr, token: Option<String>, job_ttl: Duration, prover: ProverKind) -> Result<()> {
+    info!("image id: {}, segment image id: {}, prover: {:?}", deopenchat_prover::image_id(), deopenchat_prover::segment_image_id(), prover);
+
+    // whoever reaches the daemon can have it prove, a public listener has to ask for a token
+    if token.is_none() && !bind_addr.ip().is_loopback() {
+        return Err(anyhow!("DEOPENCHAT_PROVER_TOKEN is required to listen on {}", bind_addr));
+    }
+
+    let (tx, rx) = mpsc::channel(MAX_QUEUED_JOBS);
+
+    let ctx = Arc::new(Context {
+        jobs: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(0),
+        queue: tx,
+        job_ttl,
+        prover: prover.build(),
+    });
+
+    let worker_fut = {
+        let ctx = ctx.clone();
+        tokio::task::spawn_blocking(move || worker(ctx, rx))
+    };
+
+    let app = Router::new()
+        .route("/jobs", post(submit))
+        .route("/jobs/:id", get(status))
+        .route("/jobs/:id/receipt", get(receipt))
+        .layer(DefaultBodyLimit::max(MAX_JOB_SIZE))
+        .with_state(ctx);
+
+    let app = match token {
+        Some(token) => app.route_layer(middleware::from_fn_with_state(Arc::new(token), auth)),
+        None => app,
+    };
+
+    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+    info!("Listening on http://{}", bind_addr);
+    let axum_fut = axum::serve(listener, app).into_future();
+
+    tokio::select! {
+        res = axum_fut => res?,
+        res = worker_fut => {
+            res?;
+            return Err(anyhow!("prover worker stopped"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    Daemon {
+        #[arg(long)]
+        bind_addr: SocketAddr,
+
+        #[arg(long, default_value_t = 3600)]
+        job_ttl_secs: u64,
+
+        #[arg(long, value_enum, default_value_t = ProverKind::Groth16)]
+        prover: ProverKind,
+
+        #[arg(long)]
+        mock_verifier: bool,
+    }
+}
+
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    cmd: SubCommand
+}
+
+fn exec(args: Args) -> Result<()> {
+    logger_init()?;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    match args.cmd {
+        SubCommand::Daemon {
+            bind_addr,
+            job_ttl_secs,
+            prover,
+            mock_verifier
+        } => {
+            // aggregate receipts of other provers end up as claims the contract reverts
+            ensure!(
+                prover == ProverKind::Groth16 || mock_verifier,
+                "--prover {:?} claims are rejected by the verifier, pass --mock-verifier against a mock verifier", prover
+            );
+
+            // kept out of argv, where every local user can read it
+            let token = std::env::var("DEOPENCHAT_PROVER_TOKEN").ok().filter(|t| !t.is_empty());
+            rt.block_on(daemon(bind_addr, token, Duration::from_secs(job_ttl_secs), prover))
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match exec(args) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use common::Input;
+use reqwest::{RequestBuilder, Url};
+use risc0_zkvm::Receipt;
+use serde::{Deserialize, Serialize};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+pub enum Job {
+    // rounds into a succinct segment receipt, see `prove_segment`
+    Segment(Input),
+    // segment receipts into a groth16 claim receipt, see `aggregate`
+    Aggregate(Vec<Receipt>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Proving,
+    Done,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub status: JobStatus,
+}
+
+/// Client of the `deopenchat-prover daemon` job API.
+///
+/// Paths are joined relative to `endpoint`, a daemon behind a path prefix needs the trailing slash.
+pub struct RemoteProver {
+    client: reqwest::Client,
+    endpoint: Url,
+    token: Option<String>,
+}
+
+impl RemoteProver {
+    pub fn new(endpoint: Url, token: Option<String>) -> Self {
+        RemoteProver {
+            client: reqwest::Client::new(),
+            endpoint,
+            token,
+        }
+    }
+
+    fn auth(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    pub async fn submit(&self, job: &Job) -> Result<u64> {
+        let info: JobInfo = self.auth(self.client.post(self.endpoint.join("jobs")?))
+            .json(job)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(info.id)
+    }
+
+    pub async fn status(&self, id: u64) -> Result<JobStatus> {
+        let info: JobInfo = self.auth(self.client.get(self.endpoint.join(&format!("jobs/{}", id))?))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(info.status)
+    }
+
+    /// Receipt of a finished job, it carries the seal and the journal.
+    pub async fn receipt(&self, id: u64) -> Result<Receipt> {
+        let receipt = self.auth(self.client.get(self.endpoint.join(&format!("jobs/{}/receipt", id))?))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(receipt)
+    }
+
+    /// Submits `job` and polls until it is proven.
+    pub async fn prove(&self, job: &Job) -> Result<Receipt> {
+        let id = self.submit(job).await?;
+
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            match self.status(id).await? {
+                JobStatus::Done => return self.receipt(id).await,
+                JobStatus::Failed(e) => return Err(anyhow!("prover job {} failed: {}", id, e)),
+                JobStatus::Queued | JobStatus::Proving => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use common::circuit;
+use common::AggregateInput;
+use risc0_zkvm::guest::env;
+use risc0_zkvm::serde::to_vec;
+
+fn main() {
+    let input: AggregateInput = env::read();
+
+    for journal in &input.segments {
+        // resolved against the segment receipts the host added as assumptions
+        env::verify(input.segment_image_id, &to_vec(journal).unwrap()).unwrap();
+    }
+
+    let journal = circuit::merge_segments(input.segment_image_id, &input.segments)
+        .unwrap_or_else(|e| panic!("invalid segments: {}", e));
+    env::commit_slice(&journal);
+}